@@ -1,10 +1,9 @@
 use advent_of_code::template::RunType;
 use anyhow::{anyhow, Context};
-use std::collections::BTreeMap;
 
 advent_of_code::solution!(2);
 
-#[derive(Debug, Ord, PartialOrd, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq)]
 pub enum Color {
     Red,
     Blue,
@@ -24,51 +23,169 @@ impl std::str::FromStr for Color {
     }
 }
 
-#[derive(Debug)]
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Blue => write!(f, "blue"),
+            Self::Red => write!(f, "red"),
+            Self::Green => write!(f, "green"),
+        }
+    }
+}
+
+// A number as it appeared in the input. We keep the original text around so
+// that things like leading zeros survive a round trip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Number {
+    value: u32,
+    text: String,
+}
+
+impl std::str::FromStr for Number {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(Number {
+            value: input.parse()?,
+            text: input.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cube {
+    color: Color,
+    count: Number,
+    leading: String,
+    trailing: String,
+}
+
+impl std::str::FromStr for Cube {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let trimmed = input.trim_start();
+        let leading = &input[..input.len() - trimmed.len()];
+        let body = trimmed.trim_end();
+        let trailing = &trimmed[body.len()..];
+
+        let (count, color) = body
+            .split_once(' ')
+            .context("Cube pair was missing a space")?;
+
+        Ok(Cube {
+            color: color.parse().context("Failed to parse cube color")?,
+            count: count.parse().context("Failed to parse cube count")?,
+            leading: leading.to_string(),
+            trailing: trailing.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Cube {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{} {}{}",
+            self.leading, self.count, self.color, self.trailing
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Draw(Vec<Cube>);
+
+impl Draw {
+    fn count(&self, target_color: Color) -> Option<u32> {
+        self.0
+            .iter()
+            .find(|cube| cube.color == target_color)
+            .map(|cube| cube.count.value)
+    }
+}
+
+impl std::str::FromStr for Draw {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut cubes: Vec<Cube> = Vec::new();
+        for cube in input.split(',') {
+            let cube: Cube = cube.parse()?;
+            if cubes.iter().any(|other| other.color == cube.color) {
+                return Err(anyhow!("Duplicate color {:?} found", cube.color));
+            }
+            cubes.push(cube);
+        }
+        Ok(Draw(cubes))
+    }
+}
+
+impl std::fmt::Display for Draw {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, cube) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", cube)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct GameData {
-    id: u32,
-    combos: Vec<BTreeMap<Color, u32>>,
+    label: String,
+    id: Number,
+    draws: Vec<Draw>,
 }
 
 impl std::str::FromStr for GameData {
     type Err = anyhow::Error;
     fn from_str(input: &str) -> Result<Self, Self::Err> {
         let (left, right) = input.split_once(": ").context("All lines must have a :")?;
-        let game_id: u32 = left
-            .split_once(' ')
-            .context("Failed to extract game id")?
-            .1
+        let (label, game_id) = left.split_once(' ').context("Failed to extract game id")?;
+        let game_id: Number = game_id
             .parse()
             .context("Failed to convert game_id to int")?;
 
-        let mut combos = Vec::new();
+        let mut draws = Vec::new();
         for combination in right.split(';') {
-            let mut cube_counts = BTreeMap::new();
-            for cube in combination.split(',') {
-                let (count, color) = cube
-                    .trim()
-                    .split_once(' ')
-                    .context("Cube pair was missing a space")?;
-
-                let color: Color = color.parse().context("Failed to parse cube color")?;
-                if cube_counts.contains_key(&color) {
-                    return Err(anyhow!("Duplicate color {:?} found", color));
-                }
-                cube_counts.insert(color, count.parse().context("Failed to parse cube count")?);
-            }
-            combos.push(cube_counts);
+            draws.push(combination.parse()?);
         }
         Ok(GameData {
+            label: label.to_string(),
             id: game_id,
-            combos,
+            draws,
         })
     }
 }
 
+impl std::fmt::Display for GameData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: ", self.label, self.id)?;
+        for (i, draw) in self.draws.iter().enumerate() {
+            if i != 0 {
+                write!(f, ";")?;
+            }
+            write!(f, "{}", draw)?;
+        }
+        Ok(())
+    }
+}
+
 impl GameData {
     fn is_possible(&self, limits: fn(&Color) -> u32) -> bool {
-        for combo in &self.combos {
-            let impossible = combo.iter().any(|(color, count)| *count > limits(color));
+        for draw in &self.draws {
+            let impossible = draw
+                .0
+                .iter()
+                .any(|cube| cube.count.value > limits(&cube.color));
 
             if impossible {
                 return false;
@@ -78,11 +195,10 @@ impl GameData {
     }
 
     fn minimum_cubes(&self, target_color: Color) -> Option<u32> {
-        self.combos
+        self.draws
             .iter()
-            .filter_map(|cube_counts| cube_counts.get(&target_color))
+            .filter_map(|draw| draw.count(target_color))
             .max()
-            .copied()
     }
 
     fn game_power(&self) -> u32 {
@@ -92,26 +208,102 @@ impl GameData {
     }
 }
 
-fn parse_games(input: &str) -> anyhow::Result<Vec<GameData>> {
+// Every game in the input along with the line ending that followed it, so
+// that writing it back out reproduces the original input exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Games(Vec<(GameData, String)>);
+
+impl Games {
+    fn iter(&self) -> impl Iterator<Item = &GameData> {
+        self.0.iter().map(|(game, _)| game)
+    }
+
+    #[allow(dead_code)]
+    fn to_csv(&self) -> String {
+        let mut out = String::from("game,draw,color,count\n");
+        for game in self.iter() {
+            for (draw_idx, draw) in game.draws.iter().enumerate() {
+                for cube in &draw.0 {
+                    out.push_str(&format!(
+                        "{},{},{},{}\n",
+                        game.id.value, draw_idx, cube.color, cube.count.value
+                    ));
+                }
+            }
+        }
+        out
+    }
+
+    #[allow(dead_code)]
+    fn to_json(&self) -> String {
+        let games: Vec<String> = self
+            .iter()
+            .map(|game| {
+                let draws: Vec<String> = game
+                    .draws
+                    .iter()
+                    .map(|draw| {
+                        let cubes: Vec<String> = draw
+                            .0
+                            .iter()
+                            .map(|cube| {
+                                format!(
+                                    "{{\"color\":\"{}\",\"count\":{}}}",
+                                    cube.color, cube.count.value
+                                )
+                            })
+                            .collect();
+                        format!("[{}]", cubes.join(","))
+                    })
+                    .collect();
+                format!(
+                    "{{\"id\":{},\"draws\":[{}]}}",
+                    game.id.value,
+                    draws.join(",")
+                )
+            })
+            .collect();
+        format!("[{}]", games.join(","))
+    }
+}
+
+impl std::fmt::Display for Games {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (game, line_ending) in &self.0 {
+            write!(f, "{}{}", game, line_ending)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_games(input: &str) -> anyhow::Result<Games> {
     let mut out = Vec::new();
-    for line in input.lines() {
-        out.push(line.parse().context("Failed to parse game data")?);
+    for line in input.split_inclusive('\n') {
+        let content = line
+            .strip_suffix("\r\n")
+            .or_else(|| line.strip_suffix('\n'))
+            .unwrap_or(line);
+        let line_ending = &line[content.len()..];
+        out.push((
+            content.parse().context("Failed to parse game data")?,
+            line_ending.to_string(),
+        ));
     }
-    Ok(out)
+    Ok(Games(out))
 }
 
 pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
     let data = parse_games(input).context("failed to parse input data")?;
 
     let mut possible = 0;
-    for game in data {
+    for game in data.iter() {
         let is_possible = game.is_possible(|color| match color {
             Color::Red => 12,
             Color::Green => 13,
             Color::Blue => 14,
         });
         if is_possible {
-            possible += game.id;
+            possible += game.id.value;
         }
     }
     Ok(Some(possible))
@@ -143,4 +335,32 @@ mod tests {
         assert_eq!(result, Some(2286));
         Ok(())
     }
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        assert_eq!(parse_games(input)?.to_string(), *input);
+
+        let input = "Game 007:  3 blue ,4 red;1 red, 02 green\r\nGame 2: 1 blue";
+        assert_eq!(parse_games(input)?.to_string(), input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export() -> anyhow::Result<()> {
+        let games = parse_games("Game 1: 3 blue, 4 red; 2 green\nGame 2: 1 red\n")?;
+        assert_eq!(
+            games.to_csv(),
+            "game,draw,color,count\n1,0,blue,3\n1,0,red,4\n1,1,green,2\n2,0,red,1\n"
+        );
+        assert_eq!(
+            games.to_json(),
+            concat!(
+                r#"[{"id":1,"draws":[[{"color":"blue","count":3},{"color":"red","count":4}],"#,
+                r#"[{"color":"green","count":2}]]},"#,
+                r#"{"id":2,"draws":[[{"color":"red","count":1}]]}]"#,
+            )
+        );
+        Ok(())
+    }
 }
@@ -1,19 +1,27 @@
 use advent_of_code::template::RunType;
-use anyhow::anyhow;
-use std::collections::BTreeMap;
+use anyhow::{anyhow, Context};
+use std::collections::{BTreeMap, BTreeSet};
 
 advent_of_code::solution!(3);
 
-pub fn extract_numbers(line: &str) -> Vec<(usize, usize, u32)> {
+// All columns in the schematic are counted in chars rather than bytes so that
+// schematics containing multi-byte symbols still line up.
+pub fn extract_numbers(line: &str) -> anyhow::Result<Vec<(usize, usize, u32)>> {
     let mut numbers = Vec::new();
 
     let mut start = None;
-    let mut number = 0;
-    for (char_num, c) in line.char_indices() {
+    let mut number: u32 = 0;
+    let mut width = 0;
+    for (char_num, c) in line.chars().enumerate() {
+        width = char_num + 1;
         match (c.to_digit(10), start) {
-            (Some(digit), Some(_)) => {
-                number *= 10;
-                number += digit;
+            (Some(digit), Some(start_char)) => {
+                number = number
+                    .checked_mul(10)
+                    .and_then(|number| number.checked_add(digit))
+                    .with_context(|| {
+                        format!("Number starting at column {} is too large", start_char)
+                    })?;
             }
             (Some(digit), None) => {
                 start = Some(char_num);
@@ -27,113 +35,365 @@ pub fn extract_numbers(line: &str) -> Vec<(usize, usize, u32)> {
         }
     }
     if let Some(start_char) = start {
-        numbers.push((start_char, line.len() - 1, number));
+        numbers.push((start_char, width - 1, number));
     }
-    numbers
+    Ok(numbers)
 }
 
-fn build_symbols(input: &str, is_symbol: fn(char) -> bool) -> Vec<Vec<bool>> {
-    let mut symbols = Vec::new();
-    for line in input.lines() {
-        let mut line_symbols = Vec::with_capacity(line.len());
-        for c in line.chars() {
-            line_symbols.push(is_symbol(c));
-        }
-        symbols.push(line_symbols);
-    }
+fn is_symbol(c: char) -> bool {
+    !(c.is_ascii_digit() || c == '.')
+}
 
-    symbols
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumberSpan {
+    row: usize,
+    start: usize,
+    end: usize,
+    value: u32,
 }
 
-fn test_surroundings<F>(input: &str, mut test: F)
-where
-    F: FnMut(u32, &Vec<usize>, &Vec<usize>),
-{
-    let lines: Vec<&str> = input.lines().collect();
-    for (line_num, line) in lines.iter().enumerate() {
-        let mut rows = Vec::new();
-        if line_num != 0 {
-            rows.push(line_num - 1);
-        }
-        rows.push(line_num);
-        if line_num + 1 < lines.len() {
-            rows.push(line_num + 1);
-        }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    row: usize,
+    col: usize,
+    symbol: char,
+}
 
-        for (start_num, end_num, number) in extract_numbers(line) {
-            let mut cols = Vec::new();
-            if start_num != 0 {
-                cols.push(start_num - 1);
-            }
-            for col in start_num..=end_num {
-                cols.push(col);
+// Every number and symbol in the schematic along with which numbers touch
+// which symbols. The adjacency is stored in both directions so queries from
+// either side are just a lookup.
+#[derive(Debug, Clone)]
+pub struct Schematic {
+    numbers: Vec<NumberSpan>,
+    symbols: Vec<Symbol>,
+    number_symbols: Vec<Vec<usize>>,
+    symbol_numbers: Vec<Vec<usize>>,
+}
+
+impl std::str::FromStr for Schematic {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut numbers = Vec::new();
+        let mut symbols = Vec::new();
+        let mut symbol_positions = BTreeMap::new();
+        for (row, line) in input.lines().enumerate() {
+            for (start, end, value) in extract_numbers(line)
+                .with_context(|| format!("Failed to read numbers on row {}", row))?
+            {
+                numbers.push(NumberSpan {
+                    row,
+                    start,
+                    end,
+                    value,
+                });
             }
-            if end_num + 1 < line.len() {
-                cols.push(end_num + 1);
+            for (col, c) in line.chars().enumerate() {
+                if is_symbol(c) {
+                    symbol_positions.insert((row, col), symbols.len());
+                    symbols.push(Symbol {
+                        row,
+                        col,
+                        symbol: c,
+                    });
+                }
             }
+        }
 
-            test(number, &rows, &cols);
+        let mut number_symbols = vec![Vec::new(); numbers.len()];
+        let mut symbol_numbers = vec![Vec::new(); symbols.len()];
+        for (number_idx, number) in numbers.iter().enumerate() {
+            for row in number.row.saturating_sub(1)..=number.row + 1 {
+                for col in number.start.saturating_sub(1)..=number.end + 1 {
+                    if let Some(symbol_idx) = symbol_positions.get(&(row, col)) {
+                        number_symbols[number_idx].push(*symbol_idx);
+                        symbol_numbers[*symbol_idx].push(number_idx);
+                    }
+                }
+            }
         }
+
+        Ok(Schematic {
+            numbers,
+            symbols,
+            number_symbols,
+            symbol_numbers,
+        })
     }
 }
 
-fn any_matching(rows: &Vec<usize>, cols: &Vec<usize>, symbols: &[Vec<bool>]) -> bool {
-    for row in rows {
-        for col in cols {
-            if symbols[*row][*col] {
-                return true;
+impl Schematic {
+    fn part_numbers(&self) -> impl Iterator<Item = &NumberSpan> {
+        self.numbers
+            .iter()
+            .zip(self.number_symbols.iter())
+            .filter(|(_, symbols)| !symbols.is_empty())
+            .map(|(number, _)| number)
+    }
+
+    #[allow(dead_code)]
+    fn symbols_with_neighbours(&self, count: usize) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .zip(self.symbol_numbers.iter())
+            .filter(move |(_, numbers)| numbers.len() == count)
+            .map(|(symbol, _)| symbol)
+    }
+
+    fn neighbours(&self, symbol_idx: usize) -> impl Iterator<Item = &NumberSpan> {
+        self.symbol_numbers[symbol_idx]
+            .iter()
+            .map(|number_idx| &self.numbers[*number_idx])
+    }
+
+    fn gear_ratios(&self, gear: char, policy: &GearPolicy) -> anyhow::Result<Vec<u32>> {
+        let mut out = Vec::new();
+        for (symbol_idx, symbol) in self.symbols.iter().enumerate() {
+            if symbol.symbol != gear {
+                continue;
+            }
+            let members: Vec<u32> = self
+                .neighbours(symbol_idx)
+                .map(|number| number.value)
+                .collect();
+            if let Some(ratio) = policy.ratio(&members)? {
+                out.push(ratio);
             }
         }
+        Ok(out)
     }
-    false
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
-    let symbols = build_symbols(input, |c| !(c.is_ascii_digit() || c == '.'));
+// Decides which gears count and what their ratio is, given the values of the
+// numbers touching the gear symbol.
+#[derive(Debug, Clone, Default)]
+pub enum GearPolicy {
+    // Exactly two members gives their product, fewer is ignored and more is an
+    // error. This is what the puzzle describes.
+    #[default]
+    Pairs,
+    // Exactly N members gives their product, anything else is ignored.
+    Exactly(usize),
+    // N or more members gives their product, anything else is ignored.
+    AtLeast(usize),
+    // Reduce the members however you like, returning None to ignore the gear.
+    Custom(fn(&[u32]) -> anyhow::Result<Option<u32>>),
+}
 
-    let mut out = 0;
-    test_surroundings(input, |number, rows, cols| {
-        if any_matching(rows, cols, &symbols) {
-            out += number;
+impl GearPolicy {
+    fn ratio(&self, members: &[u32]) -> anyhow::Result<Option<u32>> {
+        let matches = match self {
+            Self::Pairs => match members.len() {
+                0 | 1 => false,
+                2 => true,
+                other => {
+                    return Err(anyhow!(
+                        "Unexpected number of members in gear ratio: {}",
+                        other
+                    ));
+                }
+            },
+            Self::Exactly(count) => members.len() == *count,
+            Self::AtLeast(count) => members.len() >= *count,
+            Self::Custom(reducer) => return reducer(members),
+        };
+        if !matches {
+            return Ok(None);
         }
-    });
-    Ok(Some(out))
+
+        members
+            .iter()
+            .try_fold(1_u32, |acc, member| acc.checked_mul(*member))
+            .map(Some)
+            .context("Gear ratio overflowed")
+    }
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
-    let symbols = build_symbols(input, |c| c == '*');
-
-    let mut gears = BTreeMap::new();
-
-    test_surroundings(input, |number, rows, cols| {
-        for row in rows {
-            for col in cols {
-                if symbols[*row][*col] {
-                    gears
-                        .entry((*row, *col))
-                        .and_modify(|e: &mut Vec<u32>| e.push(number))
-                        .or_insert_with(|| vec![number]);
-                }
+// An editable schematic that keeps the part number sum and gear ratio sum up
+// to date as single cells change. Each edit only looks at the numbers touching
+// the edited cell and the gears touching those numbers.
+#[derive(Debug, Clone)]
+pub struct LiveSchematic {
+    grid: Vec<Vec<char>>,
+    gear: char,
+    policy: GearPolicy,
+    part_sum: u64,
+    gear_sum: u64,
+}
+
+impl LiveSchematic {
+    #[allow(dead_code)]
+    fn new(input: &str, gear: char, policy: GearPolicy) -> anyhow::Result<Self> {
+        let schematic: Schematic = input.parse()?;
+        let part_sum = schematic
+            .part_numbers()
+            .map(|number| number.value as u64)
+            .sum();
+        let gear_sum = schematic
+            .gear_ratios(gear, &policy)?
+            .into_iter()
+            .map(|ratio| ratio as u64)
+            .sum();
+
+        Ok(LiveSchematic {
+            grid: input.lines().map(|line| line.chars().collect()).collect(),
+            gear,
+            policy,
+            part_sum,
+            gear_sum,
+        })
+    }
+
+    fn get(&self, row: usize, col: usize) -> Option<char> {
+        self.grid.get(row).and_then(|line| line.get(col)).copied()
+    }
+
+    fn neighbourhood(
+        &self,
+        row: usize,
+        start: usize,
+        end: usize,
+    ) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (row.saturating_sub(1)..=row + 1)
+            .flat_map(move |r| (start.saturating_sub(1)..=end + 1).map(move |c| (r, c)))
+            .filter(|(r, c)| self.get(*r, *c).is_some())
+    }
+
+    fn number_at(&self, row: usize, col: usize) -> anyhow::Result<Option<NumberSpan>> {
+        let Some(line) = self.grid.get(row) else {
+            return Ok(None);
+        };
+        if !line.get(col).is_some_and(|c| c.is_ascii_digit()) {
+            return Ok(None);
+        }
+        let mut start = col;
+        while start > 0 && line[start - 1].is_ascii_digit() {
+            start -= 1;
+        }
+        let mut end = col;
+        while end + 1 < line.len() && line[end + 1].is_ascii_digit() {
+            end += 1;
+        }
+        let (_, _, value) = extract_numbers(&line[start..=end].iter().collect::<String>())
+            .with_context(|| format!("Failed to read number at ({}, {})", row, start))?[0];
+        Ok(Some(NumberSpan {
+            row,
+            start,
+            end,
+            value,
+        }))
+    }
+
+    fn numbers_touching(&self, row: usize, col: usize) -> anyhow::Result<Vec<NumberSpan>> {
+        let mut numbers = BTreeMap::new();
+        for (r, c) in self.neighbourhood(row, col, col) {
+            if let Some(number) = self.number_at(r, c)? {
+                numbers.insert((number.row, number.start), number);
             }
         }
-    });
-
-    let mut out = 0;
-    for (_, members) in gears.into_iter() {
-        match members.len() {
-            1 => continue,
-            2 => {
-                out += members.iter().product::<u32>();
+        Ok(numbers.into_values().collect())
+    }
+
+    fn is_part(&self, number: &NumberSpan) -> bool {
+        self.neighbourhood(number.row, number.start, number.end)
+            .any(|(r, c)| self.get(r, c).map(is_symbol).unwrap_or(false))
+    }
+
+    fn gear_ratio(&self, row: usize, col: usize) -> anyhow::Result<Option<u32>> {
+        if self.get(row, col) != Some(self.gear) {
+            return Ok(None);
+        }
+        let members: Vec<u32> = self
+            .numbers_touching(row, col)?
+            .into_iter()
+            .map(|number| number.value)
+            .collect();
+        self.policy.ratio(&members)
+    }
+
+    fn local_sums(
+        &self,
+        numbers: &[NumberSpan],
+        gears: &BTreeSet<(usize, usize)>,
+    ) -> anyhow::Result<(u64, u64)> {
+        let part_sum = numbers
+            .iter()
+            .filter(|number| self.is_part(number))
+            .map(|number| number.value as u64)
+            .sum();
+        let mut gear_sum = 0;
+        for (row, col) in gears {
+            gear_sum += self.gear_ratio(*row, *col)?.unwrap_or(0) as u64;
+        }
+        Ok((part_sum, gear_sum))
+    }
+
+    fn set_cell(&mut self, row: usize, col: usize, value: char) -> anyhow::Result<()> {
+        let previous = self
+            .get(row, col)
+            .with_context(|| format!("Cell ({}, {}) is outside the schematic", row, col))?;
+
+        let before = self.numbers_touching(row, col)?;
+        self.grid[row][col] = value;
+        let after = self.numbers_touching(row, col);
+        self.grid[row][col] = previous;
+        let after = after?;
+
+        // Any gear whose members could have changed is next to one of the
+        // numbers we just found, or is the edited cell itself.
+        let mut gears = BTreeSet::from([(row, col)]);
+        for number in before.iter().chain(after.iter()) {
+            gears.extend(self.neighbourhood(number.row, number.start, number.end));
+        }
+
+        let (parts_before, gears_before) = self.local_sums(&before, &gears)?;
+        self.grid[row][col] = value;
+        let (parts_after, gears_after) = match self.local_sums(&after, &gears) {
+            Ok(sums) => sums,
+            Err(e) => {
+                self.grid[row][col] = previous;
+                return Err(e);
             }
-            other => {
-                return Err(anyhow!(
-                    "Unexpected number of members in gear ratio: {}",
-                    other
-                ));
+        };
+
+        self.part_sum = self.part_sum + parts_after - parts_before;
+        self.gear_sum = self.gear_sum + gears_after - gears_before;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    fn clear_cell(&mut self, row: usize, col: usize) -> anyhow::Result<()> {
+        self.set_cell(row, col, '.')
+    }
+}
+
+impl std::fmt::Display for LiveSchematic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, line) in self.grid.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
             }
+            write!(f, "{}", line.iter().collect::<String>())?;
         }
+        Ok(())
     }
-    Ok(Some(out))
+}
+
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    let schematic: Schematic = input.parse()?;
+    Ok(Some(
+        schematic.part_numbers().map(|number| number.value).sum(),
+    ))
+}
+
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    let schematic: Schematic = input.parse()?;
+    Ok(Some(
+        schematic
+            .gear_ratios('*', &GearPolicy::default())?
+            .into_iter()
+            .sum(),
+    ))
 }
 
 #[cfg(test)]
@@ -155,4 +415,164 @@ mod tests {
         assert_eq!(result, Some(467835));
         Ok(())
     }
+
+    #[test]
+    fn test_schematic_queries() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let schematic: Schematic = input.parse()?;
+
+        assert_eq!(schematic.numbers.len(), 10);
+        assert_eq!(schematic.symbols.len(), 6);
+
+        let lonely: Vec<u32> = schematic
+            .numbers
+            .iter()
+            .filter(|number| !schematic.part_numbers().any(|part| part == *number))
+            .map(|number| number.value)
+            .collect();
+        assert_eq!(lonely, vec![114, 58]);
+
+        let pairs: Vec<(usize, usize, char)> = schematic
+            .symbols_with_neighbours(2)
+            .map(|symbol| (symbol.row, symbol.col, symbol.symbol))
+            .collect();
+        assert_eq!(pairs, vec![(1, 3, '*'), (8, 5, '*')]);
+
+        let policy = GearPolicy::default();
+        assert_eq!(schematic.gear_ratios('$', &policy)?, Vec::<u32>::new());
+        assert_eq!(schematic.gear_ratios('*', &policy)?, vec![16345, 451490]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gear_policies() -> anyhow::Result<()> {
+        let schematic: Schematic = "12.3\n.*..\n4.5*\n...6".parse()?;
+
+        assert!(schematic.gear_ratios('*', &GearPolicy::Pairs).is_err());
+        assert_eq!(
+            schematic.gear_ratios('*', &GearPolicy::Exactly(2))?,
+            vec![30]
+        );
+        assert_eq!(
+            schematic.gear_ratios('*', &GearPolicy::Exactly(3))?,
+            vec![240]
+        );
+        assert_eq!(
+            schematic.gear_ratios('*', &GearPolicy::AtLeast(2))?,
+            vec![240, 30]
+        );
+        assert_eq!(
+            schematic.gear_ratios(
+                '*',
+                &GearPolicy::Custom(|members| Ok(Some(members.iter().sum())))
+            )?,
+            vec![21, 11]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_byte_symbols() -> anyhow::Result<()> {
+        let schematic: Schematic = "§12..7\n..✱..\n.3..4✱\n".parse()?;
+
+        let spans: Vec<(usize, usize, usize, u32)> = schematic
+            .numbers
+            .iter()
+            .map(|number| (number.row, number.start, number.end, number.value))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![(0, 1, 2, 12), (0, 5, 5, 7), (2, 1, 1, 3), (2, 4, 4, 4)]
+        );
+
+        let parts: Vec<u32> = schematic.part_numbers().map(|n| n.value).collect();
+        assert_eq!(parts, vec![12, 3, 4]);
+        assert_eq!(
+            schematic.gear_ratios('✱', &GearPolicy::AtLeast(2))?,
+            vec![36]
+        );
+        Ok(())
+    }
+
+    fn full_sums(live: &LiveSchematic) -> anyhow::Result<(u64, u64)> {
+        let schematic: Schematic = live.to_string().parse()?;
+        let part_sum = schematic.part_numbers().map(|n| n.value as u64).sum();
+        let gear_sum = schematic
+            .gear_ratios(live.gear, &live.policy)?
+            .into_iter()
+            .map(|ratio| ratio as u64)
+            .sum();
+        Ok((part_sum, gear_sum))
+    }
+
+    #[test]
+    fn test_live_edits() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let mut live = LiveSchematic::new(input, '*', GearPolicy::default())?;
+        assert_eq!((live.part_sum, live.gear_sum), (4361, 467835));
+
+        live.set_cell(4, 3, '.')?;
+        assert_eq!((live.part_sum, live.gear_sum), (4361 - 617, 467835));
+        live.set_cell(3, 3, '*')?;
+        assert_eq!((live.part_sum, live.gear_sum), (4361, 467835 + 35 * 617));
+
+        // A third member on the new gear is rejected by the default policy
+        // and leaves the schematic untouched.
+        assert!(live.set_cell(3, 2, '7').is_err());
+        assert_eq!(live.get(3, 2), Some('.'));
+        assert_eq!((live.part_sum, live.gear_sum), full_sums(&live)?);
+
+        live.clear_cell(0, 0)?;
+        live.set_cell(0, 3, '7')?;
+        assert_eq!((live.part_sum, live.gear_sum), full_sums(&live)?);
+
+        // 4294967295 is the largest number that fits, one more digit doesn't
+        let mut live = LiveSchematic::new("*...........", '*', GearPolicy::AtLeast(1))?;
+        for (col, digit) in "4294967295".chars().enumerate() {
+            live.set_cell(0, col + 1, digit)?;
+        }
+        assert_eq!(live.part_sum, 4294967295);
+        assert!(live.set_cell(0, 11, '9').is_err());
+        assert_eq!(live.to_string(), "*4294967295.");
+        assert_eq!((live.part_sum, live.gear_sum), full_sums(&live)?);
+        assert!("1.99999999999".parse::<Schematic>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_edits_match_full_recompute() -> anyhow::Result<()> {
+        let mut seed: u64 = 0x2023_0003;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        let cells = ['.', '.', '.', '*', '#', '$', '1', '2', '5', '9'];
+        let blank = [".........."; 10].join("\n");
+        let policies = [
+            GearPolicy::Exactly(2),
+            GearPolicy::AtLeast(1),
+            GearPolicy::Custom(|members| Ok(Some(members.len() as u32))),
+        ];
+        for policy in policies {
+            let mut live = LiveSchematic::new(&blank, '*', policy)?;
+            for _ in 0..500 {
+                let row = next(10) as usize;
+                let col = next(10) as usize;
+                // Edits that make a number too large for a u32 are rejected
+                // and leave everything as it was.
+                let before = live.to_string();
+                if live
+                    .set_cell(row, col, cells[next(cells.len() as u64) as usize])
+                    .is_err()
+                {
+                    assert_eq!(live.to_string(), before);
+                }
+                assert_eq!((live.part_sum, live.gear_sum), full_sums(&live)?);
+            }
+        }
+        Ok(())
+    }
 }
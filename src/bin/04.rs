@@ -1,19 +1,58 @@
 use advent_of_code::template::RunType;
 use anyhow::{anyhow, Context};
 use std::collections::BTreeSet;
+use std::ops::Range;
 
 advent_of_code::solution!(4);
 
+// A set of card numbers. Numbers below 128 (which covers every real card) live
+// in a single bitset so intersections are just an AND and a popcount, anything
+// bigger falls back to a regular set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumberSet {
+    small: u128,
+    large: BTreeSet<u32>,
+}
+
+impl NumberSet {
+    const SMALL_LIMIT: u32 = u128::BITS;
+
+    fn contains(&self, num: u32) -> bool {
+        if num < Self::SMALL_LIMIT {
+            self.small & (1 << num) != 0
+        } else {
+            self.large.contains(&num)
+        }
+    }
+
+    fn insert(&mut self, num: u32) {
+        if num < Self::SMALL_LIMIT {
+            self.small |= 1 << num;
+        } else {
+            self.large.insert(num);
+        }
+    }
+
+    fn intersection_count(&self, other: &NumberSet) -> usize {
+        let large = if self.large.is_empty() || other.large.is_empty() {
+            0
+        } else {
+            self.large.intersection(&other.large).count()
+        };
+        (self.small & other.small).count_ones() as usize + large
+    }
+}
+
 #[derive(Clone)]
 pub struct GameData {
     card_id: u32,
-    winning_numbers: BTreeSet<u32>,
-    my_numbers: BTreeSet<u32>,
+    winning_numbers: NumberSet,
+    my_numbers: NumberSet,
 }
 
 impl GameData {
     fn matches(&self) -> usize {
-        self.my_numbers.intersection(&self.winning_numbers).count()
+        self.my_numbers.intersection_count(&self.winning_numbers)
     }
 }
 
@@ -30,22 +69,22 @@ impl std::str::FromStr for GameData {
 
         let (winning, my) = right.split_once(" | ").context("Expected to find split")?;
 
-        let mut winning_numbers = BTreeSet::new();
+        let mut winning_numbers = NumberSet::default();
         for num in winning.split_ascii_whitespace() {
             let num: u32 = num
                 .trim()
                 .parse()
                 .context("failed to parse winning numbers")?;
-            if winning_numbers.contains(&num) {
+            if winning_numbers.contains(num) {
                 return Err(anyhow!("Duplicate winning key {}", num));
             } else {
                 winning_numbers.insert(num);
             }
         }
-        let mut my_numbers = BTreeSet::new();
+        let mut my_numbers = NumberSet::default();
         for num in my.split_ascii_whitespace() {
             let num: u32 = num.trim().parse().context("failed to parse my number")?;
-            if my_numbers.contains(&num) {
+            if my_numbers.contains(num) {
                 return Err(anyhow!("Duplicate my key {}", num));
             } else {
                 my_numbers.insert(num);
@@ -59,48 +98,224 @@ impl std::str::FromStr for GameData {
     }
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
-    let mut out = 0;
-    for line in input.lines() {
-        let game_data: GameData = line.parse().unwrap();
-
-        let mut value = 0;
-        for _ in game_data
-            .my_numbers
-            .intersection(&game_data.winning_numbers)
-        {
-            if value == 0 {
-                value += 1;
-            } else {
-                value *= 2;
-            }
+// How many points a single card is worth given how many of its numbers match,
+// or None if that is too many points to count.
+pub trait ScoringRule {
+    fn score(&self, matches: usize) -> Option<u64>;
+}
+
+// Every card is worth a single point regardless of matches.
+pub struct PerCard;
+
+// The first match is worth one point and every match after doubles it.
+pub struct Doubling;
+
+// Every match is worth one point.
+pub struct Linear;
+
+// Matches score along the Fibonacci sequence: 1, 1, 2, 3, 5, ...
+pub struct Fibonacci;
+
+impl ScoringRule for PerCard {
+    fn score(&self, _matches: usize) -> Option<u64> {
+        Some(1)
+    }
+}
+
+impl ScoringRule for Doubling {
+    fn score(&self, matches: usize) -> Option<u64> {
+        match matches {
+            0 => Some(0),
+            matches => 1_u64.checked_shl(u32::try_from(matches - 1).ok()?),
         }
-        out += value;
     }
-    Ok(Some(out))
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
-    let mut cards = Vec::new();
-    let mut card_counts = Vec::new();
-    card_counts.push(0); // Fake card 0
-    for line in input.lines() {
-        let game_data: GameData = line.parse().unwrap();
-        cards.push(game_data);
-        card_counts.push(1);
-    }
-    let mut out = 0;
-    for card in &cards {
-        let added_cards = card_counts[(card.card_id) as usize];
-        out += added_cards;
-        for i in 0..card.matches() {
-            let new_card = card.card_id + i as u32 + 1;
-            if new_card <= cards.len() as u32 {
-                card_counts[new_card as usize] += added_cards;
+impl ScoringRule for Linear {
+    fn score(&self, matches: usize) -> Option<u64> {
+        u64::try_from(matches).ok()
+    }
+}
+
+impl ScoringRule for Fibonacci {
+    fn score(&self, matches: usize) -> Option<u64> {
+        // The next term is only needed if we get that far
+        let (mut a, mut b) = (0_u64, Some(1_u64));
+        for _ in 0..matches {
+            (a, b) = (b?, b.and_then(|b| a.checked_add(b)));
+        }
+        Some(a)
+    }
+}
+
+// Which cards a card wins copies of. Positions are indexes into the deck
+// sorted by card id, and a position can appear in more than one range if it is
+// won more than once.
+pub trait CopyRule {
+    fn targets(&self, position: usize, matches: usize, deck_size: usize) -> Vec<Range<usize>>;
+}
+
+// No card wins any copies.
+pub struct NoCopies;
+
+// Copies of the next `matches` cards, with any past the end of the deck lost.
+pub struct CappedAtEnd;
+
+// Copies of the next `matches` cards, carrying on from the start of the deck
+// once the end is reached.
+pub struct WrapAround;
+
+impl CopyRule for NoCopies {
+    fn targets(&self, _position: usize, _matches: usize, _deck_size: usize) -> Vec<Range<usize>> {
+        Vec::new()
+    }
+}
+
+impl CopyRule for CappedAtEnd {
+    #[allow(clippy::single_range_in_vec_init)]
+    fn targets(&self, position: usize, matches: usize, deck_size: usize) -> Vec<Range<usize>> {
+        let first = position + 1;
+        vec![first..(first + matches).min(deck_size)]
+    }
+}
+
+impl CopyRule for WrapAround {
+    fn targets(&self, position: usize, matches: usize, deck_size: usize) -> Vec<Range<usize>> {
+        let mut out = Vec::new();
+        let mut remaining = matches;
+        let mut start = (position + 1) % deck_size;
+        while remaining > 0 {
+            let end = (start + remaining).min(deck_size);
+            out.push(start..end);
+            remaining -= end - start;
+            start = 0;
+        }
+        out
+    }
+}
+
+// Plays out a set of scoring and copying rules over a deck of cards. Cards can
+// be given in any order and ids don't need to be dense, the deck is always
+// played in id order.
+#[derive(Debug, Clone)]
+pub struct CardCascade {
+    ids: Vec<u32>,
+    matches: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardReport<'a> {
+    card_id: u32,
+    matches: usize,
+    score: u64,
+    copies: u64,
+    spawned: Vec<&'a [u32]>,
+}
+
+impl CardCascade {
+    fn new(cards: impl IntoIterator<Item = GameData>) -> anyhow::Result<Self> {
+        let mut cards: Vec<(u32, usize)> = cards
+            .into_iter()
+            .map(|card| (card.card_id, card.matches()))
+            .collect();
+        cards.sort_unstable();
+
+        for pair in cards.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                return Err(anyhow!("Duplicate card id {}", pair[0].0));
             }
         }
+
+        let (ids, matches) = cards.into_iter().unzip();
+        Ok(CardCascade { ids, matches })
+    }
+
+    // Each card pays out once, when it is reached in the deck. Copies that
+    // land on cards that have already been played (e.g. by wrapping around)
+    // still count towards their total but don't win anything further.
+    fn run(
+        &self,
+        scoring: &dyn ScoringRule,
+        copying: &dyn CopyRule,
+    ) -> anyhow::Result<Vec<CardReport<'_>>> {
+        // Each card adds its copies to contiguous runs of cards so we record
+        // the start and end of each run and sum as we go. Runs that land on
+        // cards we have already played are summed up separately at the end.
+        let checked = |a: i64, b: i64| a.checked_add(b).context("Number of copies overflowed");
+        let deck_size = self.ids.len();
+        let mut pending = vec![0_i64; deck_size + 1];
+        let mut late = vec![0_i64; deck_size + 1];
+        let mut won = 0;
+        let mut out = Vec::with_capacity(deck_size);
+        for (idx, (card_id, matches)) in self.ids.iter().zip(self.matches.iter()).enumerate() {
+            won = checked(won, pending[idx])?;
+            let copies = checked(won, 1)?;
+
+            let mut spawned = Vec::new();
+            for range in copying.targets(idx, *matches, deck_size) {
+                let played = range.start..range.end.min(idx + 1);
+                if !played.is_empty() {
+                    late[played.start] = checked(late[played.start], copies)?;
+                    late[played.end] = checked(late[played.end], -copies)?;
+                }
+                let upcoming = range.start.max(idx + 1)..range.end;
+                if !upcoming.is_empty() {
+                    pending[upcoming.start] = checked(pending[upcoming.start], copies)?;
+                    pending[upcoming.end] = checked(pending[upcoming.end], -copies)?;
+                }
+                spawned.push(&self.ids[range]);
+            }
+
+            out.push(CardReport {
+                card_id: *card_id,
+                matches: *matches,
+                score: scoring
+                    .score(*matches)
+                    .with_context(|| format!("Score for card {} overflowed", card_id))?,
+                copies: copies as u64,
+                spawned,
+            });
+        }
+
+        let mut late_won = 0;
+        for (idx, report) in out.iter_mut().enumerate() {
+            late_won = checked(late_won, late[idx])?;
+            report.copies = checked(report.copies as i64, late_won)? as u64;
+        }
+        Ok(out)
     }
-    Ok(Some(out))
+}
+
+fn solve(
+    input: &str,
+    scoring: &dyn ScoringRule,
+    copying: &dyn CopyRule,
+) -> anyhow::Result<Option<u32>> {
+    let cards = input
+        .lines()
+        .map(|line| line.parse())
+        .collect::<Result<Vec<GameData>, anyhow::Error>>()
+        .context("Failed to parse cards")?;
+
+    let total = CardCascade::new(cards)?
+        .run(scoring, copying)?
+        .iter()
+        .try_fold(0_u64, |total, report| {
+            report
+                .score
+                .checked_mul(report.copies)
+                .and_then(|score| total.checked_add(score))
+        })
+        .context("Total score overflowed")?;
+    Ok(Some(total.try_into().context("Total score overflowed")?))
+}
+
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    solve(input, &Doubling, &NoCopies)
+}
+
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    solve(input, &PerCard, &CappedAtEnd)
 }
 
 #[cfg(test)]
@@ -122,4 +337,195 @@ mod tests {
         assert_eq!(result, Some(30));
         Ok(())
     }
+
+    #[test]
+    fn test_cascade_sparse_ids() -> anyhow::Result<()> {
+        let cards = [
+            "Card 40: 1 2 | 1 2",
+            "Card 3: 1 2 3 | 1 2 9",
+            "Card 17: 5 | 6",
+            "Card 9: 4 | 4",
+        ]
+        .iter()
+        .map(|line| line.parse())
+        .collect::<Result<Vec<GameData>, anyhow::Error>>()?;
+
+        let cascade = CardCascade::new(cards)?;
+        let report: Vec<(u32, usize, u64, Vec<&[u32]>)> = cascade
+            .run(&PerCard, &CappedAtEnd)?
+            .into_iter()
+            .map(|card| (card.card_id, card.matches, card.copies, card.spawned))
+            .collect();
+        assert_eq!(
+            report,
+            vec![
+                (3, 2, 1, vec![&[9, 17][..]]),
+                (9, 1, 2, vec![&[17][..]]),
+                (17, 0, 4, vec![&[][..]]),
+                (40, 2, 1, vec![&[][..]]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules() -> anyhow::Result<()> {
+        let scores: Vec<(Option<u64>, Option<u64>, Option<u64>)> = (0..6)
+            .map(|matches| {
+                (
+                    Doubling.score(matches),
+                    Linear.score(matches),
+                    Fibonacci.score(matches),
+                )
+            })
+            .collect();
+        assert_eq!(
+            scores,
+            [
+                (0, 0, 0),
+                (1, 1, 1),
+                (2, 2, 1),
+                (4, 3, 2),
+                (8, 4, 3),
+                (16, 5, 5)
+            ]
+            .map(|(a, b, c)| (Some(a), Some(b), Some(c)))
+        );
+
+        // Too many matches to score rather than wrapping around
+        assert_eq!(Doubling.score(64), Some(1 << 63));
+        assert_eq!(Doubling.score(65), None);
+        assert_eq!(Fibonacci.score(93), Some(12200160415121876738));
+        assert_eq!(Fibonacci.score(94), None);
+        let numbers = (1..=70)
+            .map(|n| (n * 3).to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let input = format!("Card 1: {} | {}\n", numbers, numbers);
+        assert!(solve(&input, &Doubling, &NoCopies).is_err());
+        assert_eq!(solve(&input, &Linear, &NoCopies)?, Some(70));
+        // Every card doubles the copies of all the cards after it
+        let input: String = (1..=70)
+            .map(|id| format!("Card {}: {} | {}\n", id, numbers, numbers))
+            .collect();
+        assert!(solve(&input, &PerCard, &CappedAtEnd).is_err());
+
+        assert_eq!(CappedAtEnd.targets(3, 4, 5), vec![4..5]);
+        assert_eq!(WrapAround.targets(3, 4, 5), vec![4..5, 0..3]);
+        assert_eq!(WrapAround.targets(1, 7, 3), vec![2..3, 0..3, 0..3]);
+
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 2);
+        assert_eq!(solve(input, &Linear, &NoCopies)?, Some(4 + 2 + 2 + 1));
+
+        // Copies wrapping back to cards that have been played are counted but
+        // don't pay out again.
+        let input = "Card 1: 1 | 1\nCard 2: 1 2 | 1 2\nCard 3: 1 2 | 1 2\n";
+        assert_eq!(solve(input, &PerCard, &CappedAtEnd)?, Some(1 + 2 + 3));
+        assert_eq!(solve(input, &PerCard, &WrapAround)?, Some(6 + 5 + 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_number_set() -> anyhow::Result<()> {
+        let card: GameData = "Card 1: 1 127 128 5000 | 127 128 5000 6000 2".parse()?;
+        assert_eq!(card.matches(), 3);
+        assert!(card.winning_numbers.contains(127));
+        assert!(card.winning_numbers.contains(5000));
+        assert!(!card.winning_numbers.contains(2));
+
+        assert!("Card 1: 1 1 | 2".parse::<GameData>().is_err());
+        assert!("Card 1: 1 | 900 900".parse::<GameData>().is_err());
+        Ok(())
+    }
+
+    // Checks the bitset against the BTreeSet it replaced on a large deck, and
+    // reports how long each takes. Every other card also draws numbers of 128
+    // and up so the fallback set gets matched too. Run with:
+    // cargo test --release --bin 04 bench_matches -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_matches() -> anyhow::Result<()> {
+        let mut seed: u64 = 0x2023_0004;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        let mut deck = String::new();
+        for card_id in 1..=200_000 {
+            let range = if card_id % 2 == 0 { 100 } else { 256 };
+            // Numbers are only distinct within each side of the card
+            let mut draw = |count: usize| {
+                let mut numbers: Vec<u64> = Vec::new();
+                while numbers.len() < count {
+                    let num = next(range);
+                    if !numbers.contains(&num) {
+                        numbers.push(num);
+                    }
+                }
+                numbers
+            };
+            let (winning, my) = (draw(10), draw(25));
+            deck.push_str(&format!(
+                "Card {}: {} | {}\n",
+                card_id,
+                winning
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                my.iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ));
+        }
+
+        let cards = deck
+            .lines()
+            .map(|line| line.parse())
+            .collect::<Result<Vec<GameData>, anyhow::Error>>()?;
+        let sets: Vec<(BTreeSet<u32>, BTreeSet<u32>)> = deck
+            .lines()
+            .map(|line| {
+                let (winning, my) = line.split_once(": ").unwrap().1.split_once(" | ").unwrap();
+                let parse = |s: &str| s.split_whitespace().map(|n| n.parse().unwrap()).collect();
+                (parse(winning), parse(my))
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let bitset: Vec<usize> = cards.iter().map(|card| card.matches()).collect();
+        let bitset_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let btree: Vec<usize> = sets
+            .iter()
+            .map(|(winning, my)| my.intersection(winning).count())
+            .collect();
+        let btree_time = start.elapsed();
+
+        assert_eq!(bitset, btree);
+        let large_matches = sets
+            .iter()
+            .filter(|(winning, my)| my.intersection(winning).any(|n| *n >= 128))
+            .count();
+        assert!(large_matches > 0);
+        let expected: u32 = btree
+            .iter()
+            .filter(|matches| **matches > 0)
+            .map(|matches| 1 << (matches - 1))
+            .sum();
+        assert_eq!(part_one(&deck, RunType::Example)?, Some(expected));
+        println!(
+            "{} cards ({} matching above 127): bitset {:?}, btreeset {:?}",
+            cards.len(),
+            large_matches,
+            bitset_time,
+            btree_time
+        );
+        Ok(())
+    }
 }
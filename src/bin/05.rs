@@ -1,82 +1,492 @@
 use advent_of_code::template::RunType;
+use anyhow::anyhow;
 use anyhow::Context;
 use itertools::Itertools;
-use std::collections::BTreeSet;
+use num_traits::PrimInt;
+use std::collections::{BTreeMap, BTreeSet};
 use std::num::ParseIntError;
+use std::ops::Range;
+use std::str::FromStr;
 
 advent_of_code::solution!(5);
 
+// Anything that can be used as a value in the almanac. This lets the same code
+// run on u64 for the real puzzle and on u128 (or something tiny for tests).
+pub trait AlmanacValue:
+    PrimInt + std::fmt::Debug + std::fmt::Display + FromStr<Err = ParseIntError>
+{
+}
+
+impl<T> AlmanacValue for T where
+    T: PrimInt + std::fmt::Debug + std::fmt::Display + FromStr<Err = ParseIntError>
+{
+}
+
+// All ranges in the map are half open. A rule (source_start, dest_start,
+// length) maps source_start..source_start + length onto
+// dest_start..dest_start + length in both directions.
 #[derive(Debug, Clone)]
-pub struct SparseMap(Vec<(u32, u32, u32)>);
-
-impl SparseMap {
-    fn lookup(&self, key: u32) -> Option<u32> {
-        for (source_start, dest_start, length) in &self.0 {
-            let delta = key as i64 - *source_start as i64;
-            if delta < 0 {
-                return None;
-            } else if delta >= *length as i64 {
-                continue;
+pub struct SparseMap<T> {
+    // (source_start, dest_start, length) sorted by source_start
+    rules: Vec<(T, T, T)>,
+    // The same rules as (dest_start, source_start, length) sorted by
+    // dest_start so we can look things up in either direction.
+    reversed: Vec<(T, T, T)>,
+}
+
+impl<T: AlmanacValue> SparseMap<T> {
+    fn identity() -> Self {
+        Self::build(Vec::new())
+    }
+
+    fn new(rules: Vec<(T, T, T)>) -> anyhow::Result<Self> {
+        for (source_start, dest_start, length) in &rules {
+            if source_start.checked_add(length).is_none()
+                || dest_start.checked_add(length).is_none()
+            {
+                return Err(anyhow!(
+                    "Map rule {:?} {:?} {:?} runs past the largest supported value",
+                    dest_start,
+                    source_start,
+                    length
+                ));
+            }
+        }
+        let map = Self::build(rules);
+        Self::check_disjoint(&map.rules, "source")?;
+        Self::check_disjoint(&map.reversed, "destination")?;
+        Ok(map)
+    }
+
+    // Lookups only ever check the last rule starting at or before a key, so
+    // sorted rules must never overlap.
+    fn check_disjoint(rules: &[(T, T, T)], side: &str) -> anyhow::Result<()> {
+        for (&(start, _, length), &(next_start, _, _)) in rules.iter().tuple_windows() {
+            if start + length > next_start {
+                return Err(anyhow!(
+                    "Map has overlapping {} ranges {:?}..{:?} and {:?}..",
+                    side,
+                    start,
+                    start + length,
+                    next_start
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Callers must make sure none of the rules overflow.
+    fn build(mut rules: Vec<(T, T, T)>) -> Self {
+        rules.retain(|(_, _, length)| !length.is_zero());
+        rules.sort();
+        let mut reversed: Vec<(T, T, T)> = rules
+            .iter()
+            .map(|&(source_start, dest_start, length)| (dest_start, source_start, length))
+            .collect();
+        reversed.sort();
+        SparseMap { rules, reversed }
+    }
+
+    fn find_rule(rules: &[(T, T, T)], key: T) -> Option<(T, T, T)> {
+        let idx = rules.partition_point(|&(start, _, _)| start <= key);
+        let &(start, mapped_start, length) = rules.get(idx.checked_sub(1)?)?;
+        if key - start < length {
+            Some((start, mapped_start, length))
+        } else {
+            None
+        }
+    }
+
+    fn find(rules: &[(T, T, T)], key: T) -> Option<T> {
+        let (start, mapped_start, _) = Self::find_rule(rules, key)?;
+        Some(mapped_start + (key - start))
+    }
+
+    fn lookup(&self, key: T) -> Option<T> {
+        Self::find(&self.rules, key)
+    }
+
+    #[allow(dead_code)]
+    fn rev_lookup(&self, key: T) -> Option<T> {
+        Self::find(&self.reversed, key)
+    }
+
+    // The (source_start, dest_start, length) rule that covers `key` when
+    // going from source to dest.
+    fn matching_rule(&self, key: T) -> Option<(T, T, T)> {
+        Self::find_rule(&self.rules, key)
+    }
+
+    // The (source_start, dest_start, length) rule that covers `key` when
+    // going backwards from dest to source.
+    fn rev_matching_rule(&self, key: T) -> Option<(T, T, T)> {
+        let (dest_start, source_start, length) = Self::find_rule(&self.reversed, key)?;
+        Some((source_start, dest_start, length))
+    }
+
+    // Takes `length` values starting at `source` that currently map to `dest`
+    // and maps them onward through this map. The output pieces are
+    // (source, dest, length) split wherever they cross the edge of a rule.
+    fn map_pieces(&self, source: T, dest: T, length: T, out: &mut Vec<(T, T, T)>) {
+        let end = dest + length;
+        let mut cursor = dest;
+        let first = self
+            .rules
+            .partition_point(|&(source_start, _, length)| source_start + length <= cursor);
+        for &(source_start, dest_start, rule_length) in &self.rules[first..] {
+            if source_start >= end {
+                break;
+            }
+            if cursor < source_start {
+                out.push((source + (cursor - dest), cursor, source_start - cursor));
+                cursor = source_start;
             }
+            let piece_end = (source_start + rule_length).min(end);
+            out.push((
+                source + (cursor - dest),
+                dest_start + (cursor - source_start),
+                piece_end - cursor,
+            ));
+            cursor = piece_end;
+        }
+        if cursor < end {
+            out.push((source + (cursor - dest), cursor, end - cursor));
+        }
+    }
 
-            return Some(dest_start + delta as u32);
+    // Maps every value in the given ranges, splitting them wherever they cross
+    // the edge of a rule. Values not covered by any rule map to themselves.
+    fn map_ranges(&self, ranges: &[Range<T>]) -> Vec<Range<T>> {
+        let mut pieces = Vec::new();
+        for range in ranges {
+            if range.start < range.end {
+                self.map_pieces(
+                    range.start,
+                    range.start,
+                    range.end - range.start,
+                    &mut pieces,
+                );
+            }
         }
-        None
+        pieces
+            .into_iter()
+            .map(|(_, dest, length)| dest..dest + length)
+            .collect()
     }
 
-    fn rev_lookup(&self, key: u32) -> Option<u32> {
-        for (source_start, dest_start, length) in &self.0 {
-            if key >= *dest_start && key <= dest_start + length {
-                let delta = key - dest_start;
-                return Some(source_start + delta);
+    // Builds a single map that is equivalent to looking a key up in this map
+    // and then looking the result up in `next`. If the maps send two keys to
+    // the same value the result can't be looked up backwards, so that is an
+    // error.
+    fn compose(&self, next: &SparseMap<T>) -> anyhow::Result<SparseMap<T>> {
+        let mut pieces = Vec::new();
+        let mut cursor = T::zero();
+        for &(source_start, dest_start, length) in &self.rules {
+            if cursor < source_start {
+                next.map_pieces(cursor, cursor, source_start - cursor, &mut pieces);
+            }
+            next.map_pieces(source_start, dest_start, length, &mut pieces);
+            cursor = source_start + length;
+        }
+
+        // Past our last rule everything maps to itself so only the rules in
+        // next matter.
+        for &(source_start, dest_start, length) in &next.rules {
+            let end = source_start + length;
+            if end > cursor {
+                let start = cursor.max(source_start);
+                pieces.push((start, dest_start + (start - source_start), end - start));
+            }
+        }
+
+        let mut rules: Vec<(T, T, T)> = Vec::new();
+        for (source, dest, length) in pieces {
+            if source == dest {
+                continue;
+            }
+            match rules.last_mut() {
+                Some((last_source, last_dest, last_length))
+                    if *last_source + *last_length == source
+                        && *last_dest + *last_length == dest =>
+                {
+                    *last_length = *last_length + length;
+                }
+                _ => rules.push((source, dest, length)),
             }
         }
-        None
+        // Every piece lies inside a rule or gap of one of the two maps so
+        // nothing here can overflow.
+        let map = SparseMap::build(rules);
+        Self::check_disjoint(&map.reversed, "destination")
+            .context("Composed map isn't one to one")?;
+        Ok(map)
     }
 }
 
+// Every map in the almanac keyed by its (source, destination) category. The
+// categories have to form a DAG. Other categories can be reached more than
+// one way, but a query is only answered when there is exactly one route
+// between its two categories.
 #[derive(Debug, Clone)]
-pub struct InputData {
-    seed_to_soil: SparseMap,
-    soil_to_fert: SparseMap,
-    fert_to_water: SparseMap,
-    water_to_light: SparseMap,
-    light_to_temp: SparseMap,
-    temp_to_humid: SparseMap,
-    humid_to_location: SparseMap,
-}
-
-impl InputData {
-    fn location_for_seed(&self, seed: u32) -> u32 {
-        let soil = self.seed_to_soil.lookup(seed).unwrap_or(seed);
-        let fert = self.soil_to_fert.lookup(soil).unwrap_or(soil);
-        let water = self.fert_to_water.lookup(fert).unwrap_or(fert);
-        let light = self.water_to_light.lookup(water).unwrap_or(water);
-        let temp = self.light_to_temp.lookup(light).unwrap_or(light);
-        let humid = self.temp_to_humid.lookup(temp).unwrap_or(temp);
-
-        self.humid_to_location.lookup(humid).unwrap_or(humid)
-    }
-
-    fn seed_for_location(&self, location: u32) -> u32 {
-        let humid = self
-            .humid_to_location
-            .rev_lookup(location)
-            .unwrap_or(location);
-        let temp = self.temp_to_humid.rev_lookup(humid).unwrap_or(humid);
-        let light = self.light_to_temp.rev_lookup(temp).unwrap_or(temp);
-        let water = self.water_to_light.rev_lookup(light).unwrap_or(light);
-        let fert = self.fert_to_water.rev_lookup(water).unwrap_or(water);
-        let soil = self.soil_to_fert.rev_lookup(fert).unwrap_or(fert);
-        self.seed_to_soil.rev_lookup(soil).unwrap_or(soil)
-    }
-}
-
-pub fn parse_map_section(section: &str) -> anyhow::Result<SparseMap> {
-    let data = section
+pub struct Almanac<T> {
+    maps: BTreeMap<(String, String), SparseMap<T>>,
+}
+
+impl<T: AlmanacValue> Almanac<T> {
+    fn new(maps: BTreeMap<(String, String), SparseMap<T>>) -> anyhow::Result<Self> {
+        let almanac = Almanac { maps };
+        let mut finished = BTreeSet::new();
+        for (source, _) in almanac.maps.keys() {
+            almanac.check_acyclic(source, &mut Vec::new(), &mut finished)?;
+        }
+        Ok(almanac)
+    }
+
+    fn destinations<'a>(&'a self, source: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.maps
+            .keys()
+            .filter(move |(map_source, _)| map_source == source)
+            .map(|(_, dest)| dest.as_str())
+    }
+
+    fn check_acyclic<'a>(
+        &'a self,
+        category: &'a str,
+        stack: &mut Vec<&'a str>,
+        finished: &mut BTreeSet<&'a str>,
+    ) -> anyhow::Result<()> {
+        if finished.contains(category) {
+            return Ok(());
+        }
+        if stack.contains(&category) {
+            return Err(anyhow!(
+                "Almanac categories form a cycle: {} -> {}",
+                stack.join(" -> "),
+                category
+            ));
+        }
+
+        stack.push(category);
+        for dest in self.destinations(category) {
+            self.check_acyclic(dest, stack, finished)?;
+        }
+        stack.pop();
+        finished.insert(category);
+        Ok(())
+    }
+
+    // How many routes lead from `category` to `to`, remembering the count
+    // for every category on the way so shared tails are only counted once.
+    fn count_routes<'a>(
+        &'a self,
+        category: &'a str,
+        to: &str,
+        counts: &mut BTreeMap<&'a str, u64>,
+    ) -> u64 {
+        if category == to {
+            return 1;
+        }
+        if let Some(count) = counts.get(category) {
+            return *count;
+        }
+        let count = self.destinations(category).fold(0_u64, |count, dest| {
+            count.saturating_add(self.count_routes(dest, to, counts))
+        });
+        counts.insert(category, count);
+        count
+    }
+
+    // The categories visited going from one category to another, including
+    // both ends.
+    fn route<'a>(&'a self, from: &'a str, to: &'a str) -> anyhow::Result<Vec<&'a str>> {
+        let mut counts = BTreeMap::new();
+        match self.count_routes(from, to, &mut counts) {
+            0 => return Err(anyhow!("No way to get from {} to {}", from, to)),
+            1 => {}
+            other => {
+                return Err(anyhow!(
+                    "Found {} different ways to get from {} to {}",
+                    other,
+                    from,
+                    to
+                ))
+            }
+        }
+
+        // With a single route exactly one destination leads anywhere at each
+        // step
+        let mut route = vec![from];
+        let mut current = from;
+        while current != to {
+            current = self
+                .destinations(current)
+                .find(|dest| *dest == to || counts.get(dest).is_some_and(|count| *count > 0))
+                .context("Lost the route")?;
+            route.push(current);
+        }
+        Ok(route)
+    }
+
+    fn map_for(&self, source: &str, dest: &str) -> &SparseMap<T> {
+        &self.maps[&(source.to_string(), dest.to_string())]
+    }
+
+    // Follows a single value from one category to another, recording which
+    // rule (if any) was used at each step.
+    #[allow(dead_code)]
+    fn trace(&self, from: &str, to: &str, value: T) -> anyhow::Result<Trace<T>> {
+        let mut steps = Vec::new();
+        let mut value = value;
+        for pair in self.route(from, to)?.windows(2) {
+            let (output, rule) = match self.map_for(pair[0], pair[1]).matching_rule(value) {
+                Some(rule) => (rule.1 + (value - rule.0), RuleMatch::from(rule)),
+                None => (value, RuleMatch::Identity),
+            };
+            steps.push(TraceStep {
+                from: pair[0].to_string(),
+                to: pair[1].to_string(),
+                input: value,
+                output,
+                rule,
+            });
+            value = output;
+        }
+        Ok(Trace(steps))
+    }
+
+    // The same as trace but starting with a value in the `to` category and
+    // working backwards to the `from` category.
+    #[allow(dead_code)]
+    fn trace_back(&self, from: &str, to: &str, value: T) -> anyhow::Result<Trace<T>> {
+        let mut steps = Vec::new();
+        let mut value = value;
+        for pair in self.route(from, to)?.windows(2).rev() {
+            let (output, rule) = match self.map_for(pair[0], pair[1]).rev_matching_rule(value) {
+                Some(rule) => (rule.0 + (value - rule.1), RuleMatch::from(rule)),
+                None => (value, RuleMatch::Identity),
+            };
+            steps.push(TraceStep {
+                from: pair[1].to_string(),
+                to: pair[0].to_string(),
+                input: value,
+                output,
+                rule,
+            });
+            value = output;
+        }
+        Ok(Trace(steps))
+    }
+
+    fn map_between(&self, from: &str, to: &str) -> anyhow::Result<SparseMap<T>> {
+        let route = self.route(from, to)?;
+        route
+            .windows(2)
+            .try_fold(SparseMap::identity(), |acc, pair| {
+                acc.compose(self.map_for(pair[0], pair[1]))
+                    .with_context(|| format!("Failed to map {} to {}", pair[0], pair[1]))
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleMatch<T> {
+    Rule { source: Range<T>, dest: Range<T> },
+    Identity,
+}
+
+impl<T: AlmanacValue> From<(T, T, T)> for RuleMatch<T> {
+    fn from((source_start, dest_start, length): (T, T, T)) -> Self {
+        RuleMatch::Rule {
+            source: source_start..source_start + length,
+            dest: dest_start..dest_start + length,
+        }
+    }
+}
+
+impl<T: AlmanacValue> std::fmt::Display for RuleMatch<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rule { source, dest } => write!(
+                f,
+                "{}..{} => {}..{}",
+                source.start, source.end, dest.start, dest.end
+            ),
+            Self::Identity => write!(f, "identity"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep<T> {
+    from: String,
+    to: String,
+    input: T,
+    output: T,
+    rule: RuleMatch<T>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace<T>(Vec<TraceStep<T>>);
+
+// Renders the trace as a table with one row per step, e.g.
+//
+// from  to    input  output  rule
+// seed  soil     79      81  50..98 => 52..100
+impl<T: AlmanacValue> std::fmt::Display for Trace<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rows = vec![[
+            "from".to_string(),
+            "to".to_string(),
+            "input".to_string(),
+            "output".to_string(),
+            "rule".to_string(),
+        ]];
+        for step in &self.0 {
+            rows.push([
+                step.from.clone(),
+                step.to.clone(),
+                step.input.to_string(),
+                step.output.to_string(),
+                step.rule.to_string(),
+            ]);
+        }
+
+        let mut widths = [0; 5];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        for row in rows {
+            writeln!(
+                f,
+                "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                row[4],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_map_section<T: AlmanacValue>(
+    section: &str,
+) -> anyhow::Result<(String, String, SparseMap<T>)> {
+    let (header, data) = section
         .split_once("map:\n")
-        .context("Expected to find map marker")?
-        .1;
+        .context("Expected to find map marker")?;
+    let (source, dest) = header
+        .trim()
+        .split_once("-to-")
+        .context("Expected map header to look like X-to-Y")?;
 
     let mut out = Vec::new();
     for line in data.lines() {
@@ -87,32 +497,36 @@ pub fn parse_map_section(section: &str) -> anyhow::Result<SparseMap> {
             .split_once(' ')
             .context("Expected at least 3 numbers in map line")?;
 
-        let dest_range_start: u32 = dest_range_start.parse()?;
-        let source_range_start: u32 = source_range_start.parse()?;
+        let dest_range_start: T = dest_range_start.parse()?;
+        let source_range_start: T = source_range_start.parse()?;
 
         out.push((source_range_start, dest_range_start, length.parse()?));
     }
-    out.sort();
-
-    Ok(SparseMap(out))
+    let map = SparseMap::new(out).with_context(|| format!("Invalid {}-to-{} map", source, dest))?;
+    Ok((source.to_string(), dest.to_string(), map))
 }
 
-pub fn parse_maps(mut sections: std::str::Split<'_, &str>) -> Result<InputData, anyhow::Error> {
-    Ok(InputData {
-        seed_to_soil: parse_map_section(sections.next().context("expected map section")?)?,
-        soil_to_fert: parse_map_section(sections.next().context("expected map section")?)?,
-        fert_to_water: parse_map_section(sections.next().context("expected map section")?)?,
-        water_to_light: parse_map_section(sections.next().context("expected map section")?)?,
-        light_to_temp: parse_map_section(sections.next().context("expected map section")?)?,
-        temp_to_humid: parse_map_section(sections.next().context("expected map section")?)?,
-        humid_to_location: parse_map_section(sections.next().context("expected map section")?)?,
-    })
+pub fn parse_maps<T: AlmanacValue>(
+    sections: std::str::Split<'_, &str>,
+) -> Result<Almanac<T>, anyhow::Error> {
+    let mut maps = BTreeMap::new();
+    for section in sections {
+        if section.trim().is_empty() {
+            continue;
+        }
+        let (source, dest, map) = parse_map_section(section)?;
+        if maps.contains_key(&(source.clone(), dest.clone())) {
+            return Err(anyhow!("Duplicate map from {} to {}", source, dest));
+        }
+        maps.insert((source, dest), map);
+    }
+    Almanac::new(maps)
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
     let mut sections = input.split("\n\n");
 
-    let seeds: BTreeSet<u32> = sections
+    let seeds: BTreeSet<u64> = sections
         .next()
         .context("Expected seeds section")?
         .split_once(": ")
@@ -120,23 +534,23 @@ pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::
         .1
         .split_whitespace()
         .map(|s| s.parse())
-        .collect::<Result<BTreeSet<u32>, ParseIntError>>()?;
+        .collect::<Result<BTreeSet<u64>, ParseIntError>>()?;
 
-    let data = parse_maps(sections)?;
+    let seed_to_location = parse_maps(sections)?.map_between("seed", "location")?;
 
     let min = seeds
         .iter()
-        .map(|seed| data.location_for_seed(*seed))
+        .map(|seed| seed_to_location.lookup(*seed).unwrap_or(*seed))
         .min()
         .context("Expected a minimum location")?;
 
     Ok(Some(min))
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
     let mut sections = input.split("\n\n");
 
-    let mut seeds = sections
+    let seeds = sections
         .next()
         .context("Expected seeds section")?
         .split_once(": ")
@@ -146,28 +560,24 @@ pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::
         .chunks(2)
         .into_iter()
         .map(|mut chunk| {
-            let start = chunk.next().unwrap().parse()?;
-            let len = chunk.next().unwrap().parse()?;
-            Ok((start, start, len))
+            let start: u64 = chunk.next().context("Expected seed start")?.parse()?;
+            let len: u64 = chunk.next().context("Expected seed length")?.parse()?;
+            let end = start.checked_add(len).context("Seed range overflowed")?;
+            Ok(start..end)
         })
-        .collect::<Result<Vec<(u32, u32, u32)>, ParseIntError>>()?;
+        .collect::<anyhow::Result<Vec<Range<u64>>>>()?;
 
-    seeds.sort();
+    let seed_to_location = parse_maps(sections)?.map_between("seed", "location")?;
 
-    let seeds = SparseMap(seeds);
-
-    let data = parse_maps(sections)?;
-
-    let first_seed = std::ops::Range {
-        start: 0,
-        end: u32::MAX,
-    }
-    .map(|location| data.seed_for_location(location))
-    .filter_map(|seed| seeds.lookup(seed))
-    .next()
-    .context("Expected at least one location")?;
+    let min = seed_to_location
+        .map_ranges(&seeds)
+        .into_iter()
+        .filter(|range| !range.is_empty())
+        .map(|range| range.start)
+        .min()
+        .context("Expected at least one location")?;
 
-    Ok(Some(data.location_for_seed(first_seed)))
+    Ok(Some(min))
 }
 
 #[cfg(test)]
@@ -189,4 +599,256 @@ mod tests {
         assert_eq!(result, Some(46));
         Ok(())
     }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_map_ranges() -> anyhow::Result<()> {
+        let (source, dest, map) = parse_map_section::<u32>("a-to-b map:\n50 10 5\n0 20 5")?;
+        assert_eq!((source.as_str(), dest.as_str()), ("a", "b"));
+        assert_eq!(
+            map.map_ranges(&[0..30]),
+            vec![0..10, 50..55, 15..20, 0..5, 25..30]
+        );
+        assert_eq!(map.map_ranges(&[12..22]), vec![52..55, 15..20, 0..2]);
+
+        let seed_to_location = example_almanac()?.map_between("seed", "location")?;
+        for seed in 0..100 {
+            let location = seed_to_location.lookup(seed).unwrap_or(seed);
+            assert_eq!(
+                seed_to_location.map_ranges(&[seed..seed + 1]),
+                vec![location..location + 1]
+            );
+        }
+        Ok(())
+    }
+
+    fn example_almanac() -> anyhow::Result<Almanac<u64>> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let mut sections = input.split("\n\n");
+        sections.next();
+        parse_maps(sections)
+    }
+
+    fn chained_lookup<T: AlmanacValue>(maps: &[SparseMap<T>], key: T) -> T {
+        maps.iter()
+            .fold(key, |key, map| map.lookup(key).unwrap_or(key))
+    }
+
+    #[test]
+    fn test_compose_example() -> anyhow::Result<()> {
+        let almanac = example_almanac()?;
+        let route = almanac.route("seed", "location")?;
+        assert_eq!(
+            route,
+            vec![
+                "seed",
+                "soil",
+                "fertilizer",
+                "water",
+                "light",
+                "temperature",
+                "humidity",
+                "location"
+            ]
+        );
+        let maps: Vec<SparseMap<u64>> = route
+            .windows(2)
+            .map(|pair| almanac.map_for(pair[0], pair[1]).clone())
+            .collect();
+
+        let seed_to_location = almanac.map_between("seed", "location")?;
+        for seed in 0..200 {
+            let location = seed_to_location.lookup(seed).unwrap_or(seed);
+            assert_eq!(location, chained_lookup(&maps, seed));
+            assert_eq!(
+                seed_to_location.rev_lookup(location).unwrap_or(location),
+                seed
+            );
+        }
+
+        let soil_to_humidity = almanac.map_between("soil", "humidity")?;
+        for soil in 0..200 {
+            assert_eq!(
+                soil_to_humidity.lookup(soil).unwrap_or(soil),
+                chained_lookup(&maps[1..6], soil)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_stages() -> anyhow::Result<()> {
+        // Reordered sections with an extra stage on the end still work
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let mut sections: Vec<&str> = input.split("\n\n").skip(1).collect();
+        sections.reverse();
+        let extra = format!(
+            "{}\n\nlocation-to-planet map:\n0 10 5\n10 0 5\n",
+            sections.join("\n\n")
+        );
+        let almanac = parse_maps::<u64>(extra.split("\n\n"))?;
+        let seed_to_planet = almanac.map_between("seed", "planet")?;
+        let seed_to_location = almanac.map_between("seed", "location")?;
+        for seed in 0..100 {
+            let location = seed_to_location.lookup(seed).unwrap_or(seed);
+            let planet = match location {
+                0..=4 => location + 10,
+                10..=14 => location - 10,
+                _ => location,
+            };
+            assert_eq!(seed_to_planet.lookup(seed).unwrap_or(seed), planet);
+        }
+
+        assert!(almanac.map_between("location", "seed").is_err());
+
+        // A second way from soil to water makes the route ambiguous
+        let extra = format!("{}\n\nsoil-to-water map:\n0 10 5\n", extra);
+        let almanac = parse_maps::<u64>(extra.split("\n\n"))?;
+        assert!(almanac.map_between("seed", "location").is_err());
+        assert!(almanac.map_between("light", "location").is_ok());
+
+        // 0..5 and 10..15 both end up at 20..25 so there's no going back
+        let squashed = "a-to-b map:\n10 0 5\n\nb-to-c map:\n20 10 5\n";
+        let almanac = parse_maps::<u64>(squashed.split("\n\n"))?;
+        assert!(almanac.map_between("a", "c").is_err());
+        assert!(almanac.map_between("a", "b").is_ok());
+
+        let cycle = "a-to-b map:\n1 2 3\n\nb-to-c map:\n1 2 3\n\nc-to-a map:\n1 2 3\n";
+        assert!(parse_maps::<u64>(cycle.split("\n\n")).is_err());
+
+        // A chain of 40 diamonds has 2^40 routes end to end, which has to be
+        // counted rather than walked
+        let diamonds = (0..40)
+            .flat_map(|i| {
+                [("c", "a"), ("c", "b"), ("a", "c"), ("b", "c")].map(|(from, to)| {
+                    let next = if from == "c" { i } else { i + 1 };
+                    format!("{}{}-to-{}{} map:\n0 0 1\n", from, i, to, next)
+                })
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let almanac = parse_maps::<u64>(diamonds.split("\n\n"))?;
+        let error = almanac.map_between("c0", "c40").unwrap_err();
+        assert!(error.to_string().contains("1099511627776"), "{}", error);
+        assert_eq!(almanac.route("c39", "b39")?, vec!["c39", "b39"]);
+        assert_eq!(almanac.route("b39", "c40")?, vec!["b39", "c40"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_half_open_ranges() -> anyhow::Result<()> {
+        let (_, _, map) = parse_map_section::<u32>("a-to-b map:\n50 10 5")?;
+        assert_eq!(map.lookup(9), None);
+        assert_eq!(map.lookup(10), Some(50));
+        assert_eq!(map.lookup(14), Some(54));
+        assert_eq!(map.lookup(15), None);
+        assert_eq!(map.rev_lookup(49), None);
+        assert_eq!(map.rev_lookup(50), Some(10));
+        assert_eq!(map.rev_lookup(54), Some(14));
+        assert_eq!(map.rev_lookup(55), None);
+
+        // 10..20 and 12..14 overlap on the source side, then 50..60 and
+        // 55..57 on the destination side
+        assert!(parse_map_section::<u32>("a-to-b map:\n50 10 10\n70 12 2").is_err());
+        assert!(parse_map_section::<u32>("a-to-b map:\n50 10 10\n55 30 2").is_err());
+        // Touching ranges are fine as they are half open
+        let (_, _, map) = parse_map_section::<u32>("a-to-b map:\n50 10 10\n60 20 2")?;
+        assert_eq!(map.lookup(20), Some(60));
+
+        assert!(parse_map_section::<u64>("a-to-b map:\n18446744073709551615 0 2").is_err());
+        assert!(parse_map_section::<u64>("a-to-b map:\n0 18446744073709551615 2").is_err());
+        let (_, _, map) = parse_map_section::<u128>("a-to-b map:\n18446744073709551615 0 2")?;
+        assert_eq!(map.lookup(1), Some(1 << 64));
+        Ok(())
+    }
+
+    // Small random maps over u8 so we can check every possible key, including
+    // the ones right at the top of the range.
+    #[test]
+    fn test_compose_matches_chained_lookups() -> anyhow::Result<()> {
+        let mut seed: u64 = 0x2023_0005;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        for _ in 0..200 {
+            let mut maps = Vec::new();
+            for _ in 0..1 + next(7) {
+                // Shuffle a handful of blocks of 0..size around which gives a
+                // map that is a bijection, just like the real almanac.
+                let size = 1 + next(255) as u8;
+                let mut cuts: Vec<u8> = (0..next(6)).map(|_| next(size as u64) as u8).collect();
+                cuts.extend([0, size]);
+                cuts.sort();
+                cuts.dedup();
+                let mut blocks: Vec<(u8, u8)> =
+                    cuts.windows(2).map(|w| (w[0], w[1] - w[0])).collect();
+                for i in (1..blocks.len()).rev() {
+                    blocks.swap(i, next(i as u64 + 1) as usize);
+                }
+
+                let mut rules = Vec::new();
+                let mut dest = 0;
+                for (source, length) in blocks {
+                    rules.push((source, dest, length));
+                    dest += length;
+                }
+                let map = SparseMap::new(rules)?;
+                for key in 0..=u8::MAX {
+                    if let Some(value) = map.lookup(key) {
+                        assert_eq!(map.rev_lookup(value), Some(key));
+                    }
+                }
+                maps.push(map);
+            }
+
+            let composed = maps[1..]
+                .iter()
+                .try_fold(maps[0].clone(), |acc, map| acc.compose(map))?;
+            for key in 0..=u8::MAX {
+                let value = composed.lookup(key).unwrap_or(key);
+                assert_eq!(value, chained_lookup(&maps, key));
+                assert_eq!(composed.rev_lookup(value).unwrap_or(value), key);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace() -> anyhow::Result<()> {
+        let almanac = example_almanac()?;
+        let trace = almanac.trace("seed", "location", 79)?;
+        let values: Vec<u64> = trace.0.iter().map(|step| step.output).collect();
+        assert_eq!(values, vec![81, 81, 81, 74, 78, 78, 82]);
+        assert_eq!(
+            trace.0[0].rule,
+            RuleMatch::Rule {
+                source: 50..98,
+                dest: 52..100
+            }
+        );
+        assert_eq!(trace.0[1].rule, RuleMatch::Identity);
+
+        let back = almanac.trace_back("seed", "location", 82)?;
+        let values: Vec<u64> = back.0.iter().map(|step| step.output).collect();
+        assert_eq!(values, vec![78, 78, 74, 81, 81, 81, 79]);
+        let rules: Vec<&RuleMatch<u64>> = back.0.iter().rev().map(|step| &step.rule).collect();
+        assert_eq!(
+            rules,
+            trace.0.iter().map(|step| &step.rule).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            almanac.trace("seed", "fertilizer", 79)?.to_string(),
+            concat!(
+                "from  to          input  output  rule\n",
+                "seed  soil           79      81  50..98 => 52..100\n",
+                "soil  fertilizer     81      81  identity\n",
+            )
+        );
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
 use advent_of_code::template::RunType;
 use anyhow::Context;
+use num_traits::{CheckedAdd, CheckedMul, CheckedSub, Num};
+use std::ops::RangeInclusive;
 
 advent_of_code::solution!(6);
 
@@ -24,17 +26,223 @@ pub fn extract_lines(input: &str) -> anyhow::Result<(&str, &str)> {
     Ok((times, distances))
 }
 
-fn calculate_race_options(time: u64, distance: u64) -> u64 {
-    let inner = (((time * time) - 4 * distance) as f64).sqrt();
-    let mut min_time = ((time as f64 - inner) / 2.0).ceil() as u64;
-    let mut max_time = ((time as f64 + inner) / 2.0).floor() as u64;
-    if (time - min_time) * min_time == distance {
-        min_time += 1;
+// Anything we can do race maths on, this covers u64, u128 and BigInt.
+pub trait RaceValue: Num + Ord + Clone + CheckedAdd + CheckedSub + CheckedMul + From<u64> {}
+
+impl<T> RaceValue for T where T: Num + Ord + Clone + CheckedAdd + CheckedSub + CheckedMul + From<u64>
+{}
+
+// Newton's method, which converges from above so we can stop as soon as it
+// stops shrinking.
+fn isqrt<T: RaceValue>(n: &T) -> T {
+    if n.is_zero() {
+        return T::zero();
+    }
+    let two = T::one() + T::one();
+    let mut x = n.clone();
+    let mut y = x.clone() / two.clone() + x.clone() % two.clone();
+    while y < x {
+        x = y;
+        y = (x.clone() + n.clone() / x.clone()) / two.clone();
+    }
+    x
+}
+
+fn travelled<T: RaceValue>(time: &T, hold: &T) -> T {
+    hold.clone() * (time.clone() - hold.clone())
+}
+
+// The (inclusive) range of hold times that beat the record distance, or None
+// if the record can't be beaten. This is the range strictly between the roots
+// of hold * (time - hold) = distance, which we find with an integer square
+// root and then nudge onto the exact boundary.
+fn winning_holds<T: RaceValue>(
+    time: &T,
+    distance: &T,
+) -> anyhow::Result<Option<RangeInclusive<T>>> {
+    let two = T::one() + T::one();
+    let four = two.clone() + two.clone();
+
+    let time_squared = time
+        .checked_mul(time)
+        .context("Race time is too large for this type")?;
+    let four_distance = four
+        .checked_mul(distance)
+        .context("Race distance is too large for this type")?;
+
+    // The best we can do is holding for half the time, if that doesn't win
+    // nothing will.
+    let beats = |hold: &T| travelled(time, hold) > *distance;
+    let half = time.clone() / two.clone();
+    if !beats(&half) {
+        return Ok(None);
+    }
+
+    let root = isqrt(&(time_squared - four_distance));
+    let mut min = ((time.clone() - root) / two).min(half);
+    while !beats(&min) {
+        min = min + T::one();
+    }
+    while !min.is_zero() && beats(&(min.clone() - T::one())) {
+        min = min - T::one();
+    }
+
+    // The distance is symmetric around half the time
+    let max = time.clone() - min.clone();
+    Ok(Some(min..=max))
+}
+
+fn checked_mul<T: RaceValue>(a: &T, b: &T) -> anyhow::Result<T> {
+    a.checked_mul(b)
+        .context("Race distance is too large for this type")
+}
+
+// How far a boat goes for a given hold time. Every model has to be unimodal,
+// never going down before `peak` and never going up after it, which is enough
+// to binary search for the edges of the winning holds.
+pub trait BoatModel {
+    fn distance<T: RaceValue>(&self, time: &T, hold: &T) -> anyhow::Result<T>;
+
+    // A hold time that goes at least as far as any other.
+    fn peak<T: RaceValue>(&self, time: &T) -> anyhow::Result<T>;
+
+    // Every (inclusive) range of hold times that beats the record distance.
+    fn winning_holds<T: RaceValue>(
+        &self,
+        time: &T,
+        distance: &T,
+    ) -> anyhow::Result<Vec<RangeInclusive<T>>> {
+        let two = T::one() + T::one();
+        let beats =
+            |hold: &T| -> anyhow::Result<bool> { Ok(self.distance(time, hold)? > *distance) };
+
+        let peak = self.peak(time)?;
+        if !beats(&peak)? {
+            return Ok(Vec::new());
+        }
+
+        // Smallest winning hold between 0 and the peak
+        let (mut low, mut high) = (T::zero(), peak.clone());
+        while low < high {
+            let mid = low.clone() + (high.clone() - low.clone()) / two.clone();
+            if beats(&mid)? {
+                high = mid;
+            } else {
+                low = mid + T::one();
+            }
+        }
+        let min = low;
+
+        // Largest winning hold between the peak and the end of the race
+        let (mut low, mut high) = (peak, time.clone());
+        while low < high {
+            let mid = low.clone() + (high.clone() - low.clone() + T::one()) / two.clone();
+            if beats(&mid)? {
+                low = mid;
+            } else {
+                high = mid - T::one();
+            }
+        }
+        Ok(vec![min..=low])
+    }
+}
+
+// The puzzle's boat, every millisecond of holding adds one to the speed.
+pub struct Linear;
+
+// Every millisecond of holding adds `rate` to the speed.
+pub struct ChargeRate {
+    rate: u64,
+}
+
+// The speed is the square of the hold time.
+pub struct Quadratic;
+
+// Just like Linear but the speed can't go above `max_speed`.
+pub struct Capped {
+    max_speed: u64,
+}
+
+impl BoatModel for Linear {
+    fn distance<T: RaceValue>(&self, time: &T, hold: &T) -> anyhow::Result<T> {
+        checked_mul(hold, &(time.clone() - hold.clone()))
+    }
+
+    fn peak<T: RaceValue>(&self, time: &T) -> anyhow::Result<T> {
+        Ok(time.clone() / (T::one() + T::one()))
+    }
+
+    fn winning_holds<T: RaceValue>(
+        &self,
+        time: &T,
+        distance: &T,
+    ) -> anyhow::Result<Vec<RangeInclusive<T>>> {
+        Ok(winning_holds(time, distance)?.into_iter().collect())
+    }
+}
+
+impl BoatModel for ChargeRate {
+    fn distance<T: RaceValue>(&self, time: &T, hold: &T) -> anyhow::Result<T> {
+        checked_mul(&T::from(self.rate), &Linear.distance(time, hold)?)
+    }
+
+    fn peak<T: RaceValue>(&self, time: &T) -> anyhow::Result<T> {
+        Linear.peak(time)
+    }
+
+    // rate * x > distance exactly when x > distance / rate (rounding down) so
+    // this is just the linear model with a shorter record.
+    fn winning_holds<T: RaceValue>(
+        &self,
+        time: &T,
+        distance: &T,
+    ) -> anyhow::Result<Vec<RangeInclusive<T>>> {
+        if self.rate == 0 {
+            return Ok(Vec::new());
+        }
+        Linear.winning_holds(time, &(distance.clone() / T::from(self.rate)))
+    }
+}
+
+impl BoatModel for Quadratic {
+    fn distance<T: RaceValue>(&self, time: &T, hold: &T) -> anyhow::Result<T> {
+        checked_mul(&checked_mul(hold, hold)?, &(time.clone() - hold.clone()))
+    }
+
+    // The real maximum is at 2/3 of the time so the best whole hold is on one
+    // side of it or the other.
+    fn peak<T: RaceValue>(&self, time: &T) -> anyhow::Result<T> {
+        let two = T::one() + T::one();
+        let three = two.clone() + T::one();
+        let below = checked_mul(&two, time)? / three;
+        let above = below.clone() + T::one();
+        if above <= *time && self.distance(time, &above)? > self.distance(time, &below)? {
+            Ok(above)
+        } else {
+            Ok(below)
+        }
     }
-    if (time - max_time) * max_time == distance {
-        max_time -= 1;
+}
+
+impl BoatModel for Capped {
+    fn distance<T: RaceValue>(&self, time: &T, hold: &T) -> anyhow::Result<T> {
+        let speed = hold.clone().min(T::from(self.max_speed));
+        checked_mul(&speed, &(time.clone() - hold.clone()))
+    }
+
+    // Holding past the cap only loses time
+    fn peak<T: RaceValue>(&self, time: &T) -> anyhow::Result<T> {
+        Ok(Linear.peak(time)?.min(T::from(self.max_speed)))
+    }
+}
+
+fn calculate_race_options(model: &impl BoatModel, time: u64, distance: u64) -> anyhow::Result<u64> {
+    // Squaring the time doesn't fit in a u64 so do the work in a u128
+    let mut out = 0;
+    for holds in model.winning_holds(&(time as u128), &(distance as u128))? {
+        out += (holds.end() - holds.start() + 1) as u64;
     }
-    max_time - min_time + 1
+    Ok(out)
 }
 
 pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
@@ -45,7 +253,7 @@ pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::
 
     let mut out = 1;
     for (time, distance) in times.zip(distances) {
-        out *= calculate_race_options(time?, distance?);
+        out *= calculate_race_options(&Linear, time?, distance?)?;
     }
     Ok(Some(out))
 }
@@ -54,12 +262,13 @@ pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::
     let (time, distance) = extract_lines(input)?;
     let time: u64 = time.replace(' ', "").parse()?;
     let distance: u64 = distance.replace(' ', "").parse()?;
-    Ok(Some(calculate_race_options(time, distance)))
+    Ok(Some(calculate_race_options(&Linear, time, distance)?))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use num_bigint::BigInt;
 
     #[test]
     fn test_part_one() -> anyhow::Result<()> {
@@ -76,4 +285,103 @@ mod tests {
         assert_eq!(result, Some(71503));
         Ok(())
     }
+
+    fn brute_force(time: u64, distance: u64) -> Option<RangeInclusive<u64>> {
+        let holds: Vec<u64> = (0..=time)
+            .filter(|hold| hold * (time - hold) > distance)
+            .collect();
+        Some(*holds.first()?..=*holds.last()?)
+    }
+
+    #[test]
+    fn test_winning_holds_brute_force() -> anyhow::Result<()> {
+        for time in 0..60_u64 {
+            for distance in 0..(time * time / 4 + 3) {
+                let expected = brute_force(time, distance);
+                assert_eq!(winning_holds(&time, &distance)?, expected);
+                assert_eq!(
+                    winning_holds(&(time as u128), &(distance as u128))?,
+                    expected
+                        .clone()
+                        .map(|r| *r.start() as u128..=*r.end() as u128)
+                );
+                assert_eq!(
+                    winning_holds(&BigInt::from(time), &BigInt::from(distance))?,
+                    expected.map(|r| BigInt::from(*r.start())..=BigInt::from(*r.end()))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_winning_holds_large() -> anyhow::Result<()> {
+        // Well beyond where an f64 can represent every integer
+        let time: u64 = (1 << 62) + 7;
+        let distance: u64 = (1 << 61) + 12345;
+        assert!(winning_holds(&time, &distance).is_err());
+
+        let holds = winning_holds(&(time as u128), &(distance as u128))?.unwrap();
+        let (min, max) = (*holds.start(), *holds.end());
+        let beats = |hold: u128| hold * (time as u128 - hold) > distance as u128;
+        assert!(beats(min) && !beats(min - 1));
+        assert!(beats(max) && !beats(max + 1));
+
+        let time = BigInt::from(10).pow(40) + 3;
+        let distance = BigInt::from(10).pow(60);
+        let holds = winning_holds(&time, &distance)?.unwrap();
+        let (min, max) = (holds.start().clone(), holds.end().clone());
+        let one = BigInt::from(1);
+        assert!(travelled(&time, &min) > distance);
+        assert!(travelled(&time, &(min.clone() - &one)) <= distance);
+        assert_eq!(max, time - min);
+        Ok(())
+    }
+
+    fn brute_force_model(model: &impl BoatModel, time: u64, distance: u64) -> Vec<u64> {
+        (0..=time)
+            .filter(|hold| model.distance(&time, hold).unwrap() > distance)
+            .collect()
+    }
+
+    fn check_model(model: &impl BoatModel) -> anyhow::Result<()> {
+        for time in 0..24_u64 {
+            let best = (0..=time)
+                .map(|hold| model.distance(&time, &hold))
+                .collect::<anyhow::Result<Vec<u64>>>()?
+                .into_iter()
+                .max()
+                .unwrap_or(0);
+            for distance in 0..best + 2 {
+                let expected = brute_force_model(model, time, distance);
+                let holds = model.winning_holds(&time, &distance)?;
+                let found: Vec<u64> = holds.clone().into_iter().flatten().collect();
+                assert_eq!(found, expected, "time {} distance {}", time, distance);
+
+                let big_holds =
+                    model.winning_holds(&BigInt::from(time), &BigInt::from(distance))?;
+                let big_holds: Vec<(BigInt, BigInt)> = big_holds
+                    .into_iter()
+                    .map(|holds| holds.into_inner())
+                    .collect();
+                let holds: Vec<(BigInt, BigInt)> = holds
+                    .into_iter()
+                    .map(|holds| (BigInt::from(*holds.start()), BigInt::from(*holds.end())))
+                    .collect();
+                assert_eq!(big_holds, holds);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_boat_models() -> anyhow::Result<()> {
+        check_model(&Linear)?;
+        check_model(&ChargeRate { rate: 3 })?;
+        check_model(&ChargeRate { rate: 0 })?;
+        check_model(&Quadratic)?;
+        check_model(&Capped { max_speed: 4 })?;
+        check_model(&Capped { max_speed: 100 })?;
+        Ok(())
+    }
 }
@@ -1,199 +1,408 @@
 use advent_of_code::template::RunType;
 use anyhow::{anyhow, Context};
-use std::cmp::Ordering;
 use std::collections::BTreeMap;
-use std::str::FromStr;
 
 advent_of_code::solution!(7);
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
-enum Card {
-    Jk,
-    N2,
-    N3,
-    N4,
-    N5,
-    N6,
-    N7,
-    N8,
-    N9,
-    T,
-    J,
-    Q,
-    K,
-    A,
+// A card is its position in the ruleset's alphabet, what that means for
+// tiebreaks and whether it is wild is up to the ruleset.
+#[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug)]
+struct Card(usize);
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum WildRank {
+    // Wild cards keep their place in the alphabet
+    Natural,
+    // Wild cards are weaker than every other card
+    Lowest,
+    // Wild cards are stronger than every other card
+    Highest,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Tiebreak {
+    // Compare hands card by card in the order they were dealt
+    Positional,
+    // Compare the largest groups first, then the remaining cards high to low
+    Kickers,
+}
+
+// A kind of hand along with the group sizes it needs, e.g. a full house needs
+// a group of 3 and a group of 2. Poker hands can also need a straight or a
+// flush.
+#[derive(Clone, Debug)]
+pub struct HandCategory {
+    name: String,
+    groups: Vec<u32>,
+    straight: bool,
+    flush: bool,
+}
+
+impl HandCategory {
+    fn new(name: &str, groups: &[u32]) -> Self {
+        HandCategory {
+            name: name.to_string(),
+            groups: groups.to_vec(),
+            straight: false,
+            flush: false,
+        }
+    }
+
+    fn straight(mut self) -> Self {
+        self.straight = true;
+        self
+    }
+
+    fn flush(mut self) -> Self {
+        self.flush = true;
+        self
+    }
+
+    // Whether a hand with the given group sizes (largest first) can make the
+    // groups we need, using wild cards to top up whatever is short. Pairing
+    // the largest groups together always needs the fewest wild cards.
+    fn matches(&self, profile: &[u32], wilds: u32) -> bool {
+        let mut groups = self.groups.clone();
+        groups.sort_unstable_by_key(|item| std::cmp::Reverse(*item));
+        let short: u32 = groups
+            .iter()
+            .enumerate()
+            .map(|(i, needed)| needed.saturating_sub(*profile.get(i).unwrap_or(&0)))
+            .sum();
+        short <= wilds
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ruleset {
+    // Every card from weakest to strongest
+    alphabet: Vec<char>,
+    wild: Vec<bool>,
+    // How strong each card is when breaking ties
+    strength: Vec<usize>,
+    hand_size: usize,
+    // Every kind of hand from weakest to strongest
+    categories: Vec<HandCategory>,
+    // When not empty every card is followed by one of these suits
+    suits: Vec<char>,
+    tiebreak: Tiebreak,
 }
 
-impl TryFrom<char> for Card {
-    type Error = anyhow::Error;
-
-    fn try_from(other: char) -> Result<Self, Self::Error> {
-        Ok(match other {
-            'Z' => Self::Jk,
-            '2' => Self::N2,
-            '3' => Self::N3,
-            '4' => Self::N4,
-            '5' => Self::N5,
-            '6' => Self::N6,
-            '7' => Self::N7,
-            '8' => Self::N8,
-            '9' => Self::N9,
-            'T' => Self::T,
-            'J' => Self::J,
-            'Q' => Self::Q,
-            'K' => Self::K,
-            'A' => Self::A,
-            unknown => {
-                return Err(anyhow!("Unknown card {}", unknown));
+impl Ruleset {
+    fn new(alphabet: &str, wild: &str, wild_rank: WildRank) -> anyhow::Result<Self> {
+        let alphabet: Vec<char> = alphabet.chars().collect();
+        for (i, c) in alphabet.iter().enumerate() {
+            if alphabet[..i].contains(c) {
+                return Err(anyhow!("Duplicate card {} in alphabet", c));
+            }
+        }
+        for c in wild.chars() {
+            if !alphabet.contains(&c) {
+                return Err(anyhow!("Wild card {} isn't in the alphabet", c));
             }
+        }
+        let wild: Vec<bool> = alphabet.iter().map(|c| wild.contains(*c)).collect();
+
+        let mut order: Vec<usize> = (0..alphabet.len()).collect();
+        match wild_rank {
+            WildRank::Natural => {}
+            WildRank::Lowest => order.sort_by_key(|card| !wild[*card]),
+            WildRank::Highest => order.sort_by_key(|card| wild[*card]),
+        }
+        let mut strength = vec![0; alphabet.len()];
+        for (rank, card) in order.into_iter().enumerate() {
+            strength[card] = rank;
+        }
+
+        Ok(Ruleset {
+            alphabet,
+            wild,
+            strength,
+            hand_size: 5,
+            categories: vec![
+                HandCategory::new("High card", &[1]),
+                HandCategory::new("One pair", &[2]),
+                HandCategory::new("Two pair", &[2, 2]),
+                HandCategory::new("Three of a kind", &[3]),
+                HandCategory::new("Full house", &[3, 2]),
+                HandCategory::new("Four of a kind", &[4]),
+                HandCategory::new("Five of a kind", &[5]),
+            ],
+            suits: Vec::new(),
+            tiebreak: Tiebreak::Positional,
         })
     }
-}
 
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug)]
-enum HandType {
-    HighCard,
-    OnePair,
-    TwoPair,
-    ThreeOfKind,
-    FullHouse,
-    FourOfKind,
-    FiveOfKind,
+    fn with_suits(mut self, suits: &str) -> Self {
+        self.suits = suits.chars().collect();
+        self
+    }
+
+    fn with_tiebreak(mut self, tiebreak: Tiebreak) -> Self {
+        self.tiebreak = tiebreak;
+        self
+    }
+
+    #[allow(dead_code)]
+    fn with_hand_size(mut self, hand_size: usize) -> anyhow::Result<Self> {
+        if hand_size == 0 {
+            return Err(anyhow!("Hands need at least one card"));
+        }
+        self.hand_size = hand_size;
+        Ok(self)
+    }
+
+    #[allow(dead_code)]
+    fn with_categories(mut self, categories: Vec<HandCategory>) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    // Standard five card poker, where aces can also play low in a straight
+    #[allow(dead_code)]
+    fn poker() -> Self {
+        Self::new("23456789TJQKA", "", WildRank::Natural)
+            .unwrap()
+            .with_suits("cdhs")
+            .with_tiebreak(Tiebreak::Kickers)
+            .with_categories(vec![
+                HandCategory::new("High card", &[1]),
+                HandCategory::new("One pair", &[2]),
+                HandCategory::new("Two pair", &[2, 2]),
+                HandCategory::new("Three of a kind", &[3]),
+                HandCategory::new("Straight", &[1]).straight(),
+                HandCategory::new("Flush", &[1]).flush(),
+                HandCategory::new("Full house", &[3, 2]),
+                HandCategory::new("Four of a kind", &[4]),
+                HandCategory::new("Straight flush", &[1]).straight().flush(),
+            ])
+    }
+
+    fn part_one() -> Self {
+        Self::new("23456789TJQKA", "", WildRank::Natural).unwrap()
+    }
+
+    // Jacks become jokers which are wild but the weakest card for tiebreaks
+    fn part_two() -> Self {
+        Self::new("23456789TJQKA", "J", WildRank::Lowest).unwrap()
+    }
+
+    fn card(&self, c: char) -> anyhow::Result<Card> {
+        self.alphabet
+            .iter()
+            .position(|other| *other == c)
+            .map(Card)
+            .with_context(|| format!("Unknown card {}", c))
+    }
+
+    fn is_wild(&self, card: Card) -> bool {
+        self.wild[card.0]
+    }
+
+    #[allow(dead_code)]
+    fn category_name(&self, hand_type: HandType) -> &str {
+        &self.categories[hand_type.0].name
+    }
+
+    // Hands sort by their type first and then by the tiebreak rule, packed
+    // into a single integer with a fixed width for each card so that sorting
+    // hands is a plain integer sort.
+    fn sort_key(&self, hand: &Hand) -> anyhow::Result<u128> {
+        let hand_type = hand.hand_type;
+        let strengths: Vec<usize> = match self.tiebreak {
+            Tiebreak::Positional => hand
+                .cards
+                .iter()
+                .map(|card| self.strength[card.0])
+                .collect(),
+            Tiebreak::Kickers if self.categories[hand_type.0].straight => {
+                // Only the top of a straight matters
+                vec![self.strength[hand.straight_high(self).context("Expected a straight")?.0]]
+            }
+            Tiebreak::Kickers => {
+                let mut hist = BTreeMap::new();
+                for card in &hand.cards {
+                    hist.entry(self.strength[card.0])
+                        .and_modify(|e| *e += 1)
+                        .or_insert(1_u32);
+                }
+                let mut groups: Vec<(u32, usize)> = hist
+                    .into_iter()
+                    .map(|(strength, count)| (count, strength))
+                    .collect();
+                groups.sort_unstable_by(|a, b| b.cmp(a));
+                groups.into_iter().map(|(_, strength)| strength).collect()
+            }
+        };
+
+        // Strengths are stored one higher so shorter tiebreaks sort first
+        let width = usize::BITS - self.alphabet.len().leading_zeros();
+        let type_width = usize::BITS - self.categories.len().leading_zeros();
+        if type_width as usize + width as usize * self.hand_size > u128::BITS as usize {
+            return Err(anyhow!(
+                "Hands of {} cards don't fit in a sort key",
+                self.hand_size
+            ));
+        }
+        let mut key = hand_type.0 as u128;
+        for i in 0..self.hand_size {
+            let strength = strengths.get(i).map_or(0, |strength| strength + 1);
+            key = key << width | strength as u128;
+        }
+        Ok(key)
+    }
 }
 
+// The position of the hand's category in the ruleset, higher is better.
+#[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug)]
+struct HandType(usize);
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 struct Hand {
     cards: Vec<Card>,
+    // One per card when the ruleset has suits
+    suits: Vec<char>,
+    hand_type: HandType,
+    key: u128,
 }
 
-impl PartialOrd for Hand {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl Hand {
+    fn parse(input: &str, ruleset: &Ruleset) -> anyhow::Result<Self> {
+        let mut cards = Vec::new();
+        let mut suits = Vec::new();
+        let mut chars = input.chars();
+        while let Some(c) = chars.next() {
+            cards.push(ruleset.card(c)?);
+            if !ruleset.suits.is_empty() {
+                let suit = chars
+                    .next()
+                    .with_context(|| format!("Expected a suit after {}", c))?;
+                if !ruleset.suits.contains(&suit) {
+                    return Err(anyhow!("Unknown suit {}", suit));
+                }
+                if cards[..cards.len() - 1]
+                    .iter()
+                    .zip(suits.iter())
+                    .any(|(card, other)| *card == cards[cards.len() - 1] && *other == suit)
+                {
+                    return Err(anyhow!("Duplicate card {}{}", c, suit));
+                }
+                suits.push(suit);
+            }
+        }
+        if cards.len() != ruleset.hand_size {
+            return Err(anyhow!(
+                "Expected {} cards in hand but found {}",
+                ruleset.hand_size,
+                cards.len()
+            ));
+        }
+
+        let mut hand = Hand {
+            cards,
+            suits,
+            hand_type: HandType(0),
+            key: 0,
+        };
+        hand.hand_type = hand.optimal_hand_type(ruleset)?;
+        hand.key = ruleset.sort_key(&hand)?;
+        Ok(hand)
+    }
+
+    fn is_flush(&self) -> bool {
+        self.suits.len() > 1 && self.suits.iter().all(|suit| *suit == self.suits[0])
     }
-}
 
-impl Ord for Hand {
-    fn cmp(&self, other: &Self) -> Ordering {
-        match self.optimal_hand_type().cmp(&other.optimal_hand_type()) {
-            Ordering::Equal => self.cards.cmp(&other.cards),
-            other => other,
+    // The top card if the hand runs through consecutive cards of the
+    // alphabet, where the last card can also sit below the first. Wild cards
+    // don't fill gaps in a straight, and a lone card doesn't run anywhere.
+    fn straight_high(&self, ruleset: &Ruleset) -> Option<Card> {
+        if self.cards.len() < 2 || self.cards.iter().any(|card| ruleset.is_wild(*card)) {
+            return None;
+        }
+        let mut cards = self.cards.clone();
+        cards.sort_unstable();
+        cards.dedup();
+        if cards.len() != self.cards.len() {
+            return None;
+        }
+        let last = cards[cards.len() - 1];
+        if cards.windows(2).all(|w| w[0].0 + 1 == w[1].0) {
+            return Some(last);
+        }
+        // Aces low, where the rest of the hand is the bottom of the alphabet
+        let rest = &cards[..cards.len() - 1];
+        if last.0 + 1 == ruleset.alphabet.len()
+            && rest.iter().enumerate().all(|(i, card)| card.0 == i)
+        {
+            return rest.last().copied();
         }
+        None
     }
-}
 
-impl Hand {
-    #[allow(clippy::get_first)]
-    fn optimal_hand_type(&self) -> HandType {
+    // The best category we can make from the sorted group sizes and however
+    // many wild cards we have.
+    fn optimal_hand_type(&self, ruleset: &Ruleset) -> anyhow::Result<HandType> {
         let mut hist = BTreeMap::new();
+        let mut wilds = 0;
         for card in &self.cards {
-            hist.entry(card).and_modify(|e| *e += 1).or_insert(1_u32);
-        }
-        let jokers = hist.remove(&Card::Jk).unwrap_or(0);
-
-        let mut hist: Vec<u32> = hist.into_values().collect();
-        hist.sort_unstable_by_key(|item| std::cmp::Reverse(*item));
-
-        match (
-            jokers,
-            hist.get(0).unwrap_or(&0),
-            hist.get(1).unwrap_or(&0),
-            hist.get(2).unwrap_or(&0),
-            hist.get(3).unwrap_or(&0),
-            hist.get(4).unwrap_or(&0),
-        ) {
-            (5, 0, ..) => HandType::FiveOfKind,
-            // We can get five of a kind from any combination of 1 card
-            // plus only jokers
-            (0, 5, ..) => HandType::FiveOfKind,
-            (1, 4, 0, ..) => HandType::FiveOfKind,
-            (2, 3, 0, ..) => HandType::FiveOfKind,
-            (3, 2, 0, ..) => HandType::FiveOfKind,
-            (4, 1, 0, ..) => HandType::FiveOfKind,
-
-            // We can make 4 of a kind by either having 0 jokers or
-            (0, 4, 1, ..) => HandType::FourOfKind,
-            (1, 3, 1, ..) => HandType::FourOfKind,
-            (2, 2, 1, ..) => HandType::FourOfKind,
-            (3, 1, 1, ..) => HandType::FourOfKind,
-
-            // FullHouse can be made in quite a few different ways but there are
-            // only two that are optimal. Either you have no jokers or you have
-            // two pair + joker that you want to upgrade to 3 of a kind + pair.
-            (0, 3, 2, ..) => HandType::FullHouse,
-            (1, 2, 2, ..) => HandType::FullHouse,
-
-            // Three of a kind
-            (0, 3, 1, 1, ..) => HandType::ThreeOfKind,
-            (1, 2, 1, 1, ..) => HandType::ThreeOfKind,
-            (2, 1, 1, 1, ..) => HandType::ThreeOfKind,
-
-            // Two Pair can't be made with jokers because
-            // having even a single joker is enough to upgrade
-            // one of the pairs to a 3 of a kind.
-            (0, 2, 2, 1, ..) => HandType::TwoPair,
-
-            // One Pair
-            (0, 2, 1, 1, 1, 0) => HandType::OnePair,
-            (1, 1, 1, 1, 1, 0) => HandType::OnePair,
-
-            // High card by definition can't have any jokers
-            (0, 1, 1, 1, 1, 1) => HandType::HighCard,
-
-            _ => {
-                panic!("Invalid histagram: {:?} with {} jokers", hist, jokers);
+            if ruleset.is_wild(*card) {
+                wilds += 1;
+            } else {
+                hist.entry(card).and_modify(|e| *e += 1).or_insert(1_u32);
             }
         }
-    }
-}
 
-impl FromStr for Hand {
-    type Err = anyhow::Error;
+        let mut profile: Vec<u32> = hist.into_values().collect();
+        profile.sort_unstable_by_key(|item| std::cmp::Reverse(*item));
+        let straight = self.straight_high(ruleset).is_some();
+        let flush = self.is_flush();
 
-    fn from_str(other: &str) -> Result<Self, Self::Err> {
-        Ok(Hand {
-            cards: other
-                .chars()
-                .map(Card::try_from)
-                .collect::<Result<Vec<Card>, Self::Err>>()?,
-        })
+        ruleset
+            .categories
+            .iter()
+            .rposition(|category| {
+                (!category.straight || straight)
+                    && (!category.flush || flush)
+                    && category.matches(&profile, wilds)
+            })
+            .map(HandType)
+            .with_context(|| {
+                format!(
+                    "Hand with groups {:?} and {} wild cards doesn't match any category",
+                    profile, wilds
+                )
+            })
     }
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+fn solve(input: &str, ruleset: &Ruleset) -> anyhow::Result<Option<u32>> {
     let mut data = input
         .lines()
         .map(|line| {
             let (hand, bet) = line.split_once(' ').context("Expected to find hand/bet")?;
-            Ok((hand.parse()?, bet.parse()?))
+            Ok((Hand::parse(hand, ruleset)?, bet.parse()?))
         })
         .collect::<Result<Vec<(Hand, u32)>, anyhow::Error>>()
-        .context("Failed to parse hand")?;
+        .context("Failed to parse hand/bet")?;
+
+    // Tied hands are ranked by their bet so the order never depends on the
+    // input
+    data.sort_unstable_by_key(|(hand, bet)| (hand.key, *bet));
 
     let mut out = 0;
-    data.sort();
     for (i, (_, bet)) in data.iter().enumerate() {
         out += (i as u32 + 1) * bet;
     }
     Ok(Some(out))
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
-    let mut data = input
-        .lines()
-        .map(|line| {
-            let (hand, bet) = line.split_once(' ').context("Expected to find hand/bet")?;
-            Ok((hand.replace('J', "Z").parse()?, bet.parse()?))
-        })
-        .collect::<Result<Vec<(Hand, u32)>, anyhow::Error>>()
-        .context("Failed to parse hand/bet")?;
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    solve(input, &Ruleset::part_one())
+}
 
-    let mut out = 0;
-    data.sort();
-    for (i, (hand, bet)) in data.iter().enumerate() {
-        hand.optimal_hand_type();
-        out += (i as u32 + 1) * bet;
-    }
-    Ok(Some(out))
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    solve(input, &Ruleset::part_two())
 }
 
 #[cfg(test)]
@@ -215,4 +424,225 @@ mod tests {
         assert_eq!(result, Some(5905));
         Ok(())
     }
+
+    fn category(ruleset: &Ruleset, hand: &str) -> anyhow::Result<String> {
+        let hand = Hand::parse(hand, ruleset)?;
+        Ok(ruleset.category_name(hand.hand_type).to_string())
+    }
+
+    #[test]
+    fn test_rulesets() -> anyhow::Result<()> {
+        let ruleset = Ruleset::new("ABCD", "AD", WildRank::Highest)?;
+        assert_eq!(category(&ruleset, "ABDCA")?, "Four of a kind");
+
+        // B < C < A < D once the wild cards move to the top
+        assert!(Hand::parse("BACCB", &ruleset)?.key < Hand::parse("BDCCB", &ruleset)?.key);
+        assert!(Hand::parse("CBBBB", &ruleset)?.key < Hand::parse("ABBBB", &ruleset)?.key);
+
+        let ruleset = Ruleset::new("ABCD", "", WildRank::Lowest)?;
+        assert_eq!(category(&ruleset, "ABDCA")?, "One pair");
+        assert!(Hand::parse("ABCDE", &ruleset).is_err());
+
+        assert!(Ruleset::new("ABCA", "", WildRank::Natural).is_err());
+        assert!(Ruleset::new("ABC", "D", WildRank::Natural).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hand_sizes() -> anyhow::Result<()> {
+        let ruleset = Ruleset::part_two();
+        assert!(Hand::parse("2345", &ruleset).is_err());
+        assert_eq!(category(&ruleset, "JJJJJ")?, "Five of a kind");
+        assert_eq!(category(&ruleset, "2J3J4")?, "Three of a kind");
+        assert_eq!(category(&ruleset, "22J33")?, "Full house");
+
+        let ruleset = Ruleset::part_two().with_hand_size(4)?;
+        assert_eq!(category(&ruleset, "2345")?, "High card");
+        assert_eq!(category(&ruleset, "22J3")?, "Three of a kind");
+        assert_eq!(category(&ruleset, "2233")?, "Two pair");
+
+        let ruleset = Ruleset::part_two().with_hand_size(6)?.with_categories(vec![
+            HandCategory::new("Nothing", &[]),
+            HandCategory::new("Pair", &[2]),
+            HandCategory::new("Three pair", &[2, 2, 2]),
+            HandCategory::new("Two triples", &[3, 3]),
+            HandCategory::new("Six of a kind", &[6]),
+        ]);
+        assert_eq!(category(&ruleset, "234567")?, "Nothing");
+        assert_eq!(category(&ruleset, "223344")?, "Three pair");
+        assert_eq!(category(&ruleset, "2223J4")?, "Pair");
+        assert_eq!(category(&ruleset, "222J33")?, "Two triples");
+        assert_eq!(category(&ruleset, "JJJJJJ")?, "Six of a kind");
+
+        // Too many cards to pack into a sort key
+        let ruleset = Ruleset::part_one().with_hand_size(40)?;
+        assert!(Hand::parse(&"2".repeat(40), &ruleset).is_err());
+
+        assert!(Ruleset::part_one().with_hand_size(0).is_err());
+
+        // A single card is neither a straight nor a flush
+        let ruleset = Ruleset::poker().with_hand_size(1)?;
+        assert_eq!(category(&ruleset, "Ah")?, "High card");
+        assert_eq!(category(&ruleset, "2c")?, "High card");
+
+        // Without a catch all category some hands can't be classified
+        let ruleset = Ruleset::part_one().with_categories(vec![HandCategory::new("Pair", &[2])]);
+        assert!(category(&ruleset, "23456").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_poker() -> anyhow::Result<()> {
+        let ruleset = Ruleset::poker();
+        assert_eq!(category(&ruleset, "AhKdTc4s2h")?, "High card");
+        assert_eq!(category(&ruleset, "5h5d9c4s2h")?, "One pair");
+        assert_eq!(category(&ruleset, "5h5d9c9s2h")?, "Two pair");
+        assert_eq!(category(&ruleset, "5h5d5c9s2h")?, "Three of a kind");
+        assert_eq!(category(&ruleset, "9h8dTc7sJh")?, "Straight");
+        assert_eq!(category(&ruleset, "Ah2d3c4s5h")?, "Straight");
+        assert_eq!(category(&ruleset, "QhKdAc2s3h")?, "High card");
+        assert_eq!(category(&ruleset, "Ah2h9h4h5h")?, "Flush");
+        assert_eq!(category(&ruleset, "5h5d5c9s9h")?, "Full house");
+        assert_eq!(category(&ruleset, "5h5d5c5s9h")?, "Four of a kind");
+        assert_eq!(category(&ruleset, "ThJhQhKhAh")?, "Straight flush");
+
+        assert!(Hand::parse("AhKdTc4s2", &ruleset).is_err());
+        assert!(Hand::parse("AhKdTc4s2x", &ruleset).is_err());
+        assert!(Hand::parse("AhAhTc4s2h", &ruleset).is_err());
+
+        // Each pair of hands is listed weakest first
+        let pairs = [
+            // A pair beats any high card
+            ("AhKdQcJs9h", "2h2d3c4s5h"),
+            // Kickers rather than position decide between high cards
+            ("KhQdJc9s8h", "2hAd3c4s6h"),
+            ("AhKdQcJs8h", "9hJdAcKsQh"),
+            // The pair itself counts before the kickers
+            ("KhKdAcQsJh", "3hAdAc2s4h"),
+            ("AhAdKcQs9h", "AcAsKhQdTh"),
+            // Highest pair, then lower pair, then the last card
+            ("KhKdQcQsAh", "AhAd2c2s3h"),
+            ("AhAd2c2s3h", "AcAs3d3h2h"),
+            ("AhAd3c3s2h", "AcAs3d3hKh"),
+            // The triple decides a full house
+            ("2h2d2cAsAh", "3h3d3c2s2h"),
+            // Aces low make the weakest straight
+            ("Ah2d3c4s5h", "2h3d4c5s6h"),
+            ("Ad2c3h4s5d", "Kh9h7h5h3h"),
+            ("AhKhQhJh9h", "2h2d2c3s3h"),
+            ("Ah2h3h4h5h", "9c8c7c6c5c"),
+        ];
+        for (weaker, stronger) in pairs {
+            assert!(Hand::parse(weaker, &ruleset)?.key < Hand::parse(stronger, &ruleset)?.key);
+        }
+        let tied = Hand::parse("9dJhQdKcAs", &ruleset)?;
+        assert_eq!(Hand::parse("AhKdQcJs9h", &ruleset)?.key, tied.key);
+
+        // Tied hands give the same winnings whatever order they come in
+        let lines = ["AhKdQcJs9h 1", "2h2d3c4s5h 100", "9dJhQdKcAs 1000"];
+        let forwards = solve(&lines.join("\n"), &ruleset)?;
+        let reversed: Vec<&str> = lines.iter().rev().copied().collect();
+        let backwards = solve(&reversed.join("\n"), &ruleset)?;
+        assert_eq!(forwards, Some(1 + 2 * 1000 + 3 * 100));
+        assert_eq!(backwards, forwards);
+        Ok(())
+    }
+
+    // Checks the cached hand types against trying every card for each joker,
+    // and that sorting by the packed key gives the same order as recomputing
+    // the hand type on every comparison. Also reports how long each takes.
+    // Run with:
+    // cargo test --release --bin 07 bench_sort -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_sort() -> anyhow::Result<()> {
+        let mut seed: u64 = 0x2023_0007;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        let ruleset = Ruleset::part_two();
+        let lines: Vec<String> = (0..1_000_000)
+            .map(|_| {
+                (0..5)
+                    .map(|_| ruleset.alphabet[next(13) as usize])
+                    .collect()
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let mut hands = lines
+            .iter()
+            .map(|line| Hand::parse(line, &ruleset))
+            .collect::<anyhow::Result<Vec<Hand>>>()?;
+        let parse_time = start.elapsed();
+
+        // Natural hands ranked by their group sizes, before trying each
+        // possible card in place of the jokers
+        let natural = |cards: &[usize]| {
+            let mut counts = [0; 13];
+            for card in cards {
+                counts[*card] += 1;
+            }
+            let mut groups: Vec<u32> = counts.into_iter().filter(|count| *count > 0).collect();
+            groups.sort_unstable_by(|a, b| b.cmp(a));
+            match groups.as_slice() {
+                [5] => 6,
+                [4, 1] => 5,
+                [3, 2] => 4,
+                [3, 1, 1] => 3,
+                [2, 2, 1] => 2,
+                [2, 1, 1, 1] => 1,
+                _ => 0,
+            }
+        };
+        let joker = ruleset.card('J')?.0;
+        for hand in hands.iter().take(20_000) {
+            let mut best = 0;
+            let mut pending = vec![hand.cards.iter().map(|card| card.0).collect::<Vec<_>>()];
+            while let Some(cards) = pending.pop() {
+                match cards.iter().position(|card| *card == joker) {
+                    Some(i) => pending.extend((0..13).filter(|card| *card != joker).map(|card| {
+                        let mut cards = cards.clone();
+                        cards[i] = card;
+                        cards
+                    })),
+                    None => best = best.max(natural(&cards)),
+                }
+            }
+            assert_eq!(hand.hand_type, HandType(best), "{:?}", hand.cards);
+        }
+
+        let start = std::time::Instant::now();
+        let mut keys: Vec<u128> = hands.iter().map(|hand| hand.key).collect();
+        keys.sort_unstable();
+        let packed_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        hands.sort_unstable_by(|a, b| {
+            let key = |hand: &Hand| {
+                let strengths: Vec<usize> = hand
+                    .cards
+                    .iter()
+                    .map(|card| ruleset.strength[card.0])
+                    .collect();
+                (hand.optimal_hand_type(&ruleset).unwrap(), strengths)
+            };
+            key(a).cmp(&key(b))
+        });
+        let recompute_time = start.elapsed();
+
+        assert!(hands.iter().map(|hand| hand.key).eq(keys.iter().copied()));
+        println!(
+            "{} hands: parse {:?}, packed sort {:?}, recomputed sort {:?}",
+            hands.len(),
+            parse_time,
+            packed_time,
+            recompute_time
+        );
+        Ok(())
+    }
 }
@@ -1,21 +1,25 @@
 use advent_of_code::template::RunType;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use nom::bytes::complete::tag;
 use nom::character::complete::{alphanumeric1, anychar, line_ending, multispace0};
 use nom::combinator::map_res;
 use nom::error::ParseError;
 use nom::multi::{many1, many_till};
 use nom::{Finish, IResult};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
 
 advent_of_code::solution!(8);
 
 type Map = BTreeMap<String, (String, String)>;
 
-#[derive(Debug)]
+// Called with the step number and every walker's position
+type Visitor<'a> = dyn FnMut(u64, &[&str]) + 'a;
+
+#[derive(Clone, Copy, Debug)]
 enum Direction {
-    Left,
-    Right,
+    Left = 0,
+    Right = 1,
 }
 
 fn nom_map_line(input: &str) -> IResult<&str, (String, (String, String))> {
@@ -65,102 +69,337 @@ fn parse_input(input: &str) -> anyhow::Result<(Vec<Direction>, Map)> {
     }
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
-    let (directions, mapping) = parse_input(input)?;
-
-    let mut out = 0;
-    let mut current = "AAA";
-    for direction in directions.iter().cycle() {
-        out += 1;
-        current = match mapping.get(current) {
-            Some((left, right)) => match direction {
-                Direction::Left => left,
-                Direction::Right => right,
-            },
-            None => {
-                return Err(anyhow!("Found non-existant key: {}", current));
-            }
-        };
+// Nodes are numbered in name order, with the left and right links of each
+// node stored side by side.
+struct Network {
+    directions: Vec<Direction>,
+    names: Vec<String>,
+    links: Vec<[u32; 2]>,
+}
+
+// One bit per node id
+struct NodeSet(Vec<u64>);
+
+impl NodeSet {
+    fn contains(&self, node: u32) -> bool {
+        self.0[node as usize / 64] & (1 << (node % 64)) != 0
+    }
+}
+
+// The result of walking the network, with every position of every walker
+// after each step (starting with where they started) if the path was
+// recorded.
+#[derive(Debug)]
+struct Walk<'a> {
+    steps: u64,
+    #[allow(dead_code)]
+    path: Vec<Vec<&'a str>>,
+}
 
-        if current == "ZZZ" {
-            return Ok(Some(out));
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (directions, mapping) = parse_input(input)?;
+        if directions.is_empty() {
+            return Err(anyhow!("Expected at least one direction"));
+        }
+        if mapping.len() > u32::MAX as usize {
+            return Err(anyhow!("Too many nodes: {}", mapping.len()));
         }
+
+        let ids: BTreeMap<&str, u32> = mapping
+            .keys()
+            .enumerate()
+            .map(|(id, name)| (name.as_str(), id as u32))
+            .collect();
+        let id = |name: &str| {
+            ids.get(name)
+                .copied()
+                .with_context(|| format!("Found non-existant key: {}", name))
+        };
+        let links = mapping
+            .values()
+            .map(|(left, right)| Ok([id(left)?, id(right)?]))
+            .collect::<anyhow::Result<Vec<[u32; 2]>>>()?;
+
+        Ok(Network {
+            directions,
+            names: mapping.into_keys().collect(),
+            links,
+        })
     }
-    Ok(None)
 }
 
-fn find_cycle<'a>(
-    directions: &[Direction],
-    mut start: &'a str,
-    mapping: &'a Map,
-) -> anyhow::Result<(usize, usize)> {
-    let mut seen = BTreeMap::new();
-    let mut out = Vec::new();
-    for (idx, direction) in directions.iter().enumerate().cycle() {
-        if let Some(cycle_start_idx) = seen.get(&(idx, start)) {
-            return Ok((*cycle_start_idx, seen.len()));
+impl Network {
+    fn step(&self, node: u32, direction: Direction) -> u32 {
+        self.links[node as usize][direction as usize]
+    }
+
+    fn name(&self, node: u32) -> &str {
+        &self.names[node as usize]
+    }
+
+    fn starts(&self, start: impl Fn(&str) -> bool) -> Vec<u32> {
+        (0..self.names.len() as u32)
+            .filter(|node| start(self.name(*node)))
+            .collect()
+    }
+
+    fn node_set(&self, member: impl Fn(&str) -> bool) -> NodeSet {
+        let mut bits = vec![0; self.names.len().div_ceil(64)];
+        for node in self.starts(member) {
+            bits[node as usize / 64] |= 1 << (node % 64);
         }
-        seen.insert((idx, start), seen.len());
-        if start.ends_with('Z') {
-            out.push((idx, start));
+        NodeSet(bits)
+    }
+
+    // Walks from every node matching `start` at once until they all match
+    // `goal`, taking at least one step. Gives up after `limit` steps if there
+    // is one, and calls `visitor` with the positions after every step. The
+    // names along the way are only looked up for the visitor or when asked to
+    // `record_path`, so plain walks just step through node ids. A walk
+    // that runs through every possible state without reaching the goal is
+    // going round in circles, so that is the limit if there isn't a smaller
+    // one.
+    fn walk(
+        &self,
+        start: impl Fn(&str) -> bool,
+        goal: impl Fn(&str) -> bool,
+        limit: Option<u64>,
+        record_path: bool,
+        mut visitor: Option<&mut Visitor>,
+    ) -> anyhow::Result<Option<Walk<'_>>> {
+        let goal = self.node_set(goal);
+        let mut current = self.starts(start);
+        if current.is_empty() {
+            return Err(anyhow!("No nodes to start from"));
         }
 
-        start = match mapping.get(start) {
-            Some((left, right)) => match direction {
-                Direction::Left => left,
-                Direction::Right => right,
-            },
-            None => {
-                return Err(anyhow!("Found non-existant key: {}", start));
+        let states = (0..current.len()).fold(self.directions.len() as u64, |states, _| {
+            states.saturating_mul(self.names.len() as u64)
+        });
+        let limit = limit.map_or(states, |limit| limit.min(states));
+
+        let mut path = Vec::new();
+        let mut names = Vec::with_capacity(current.len());
+        let mut visit = |steps: u64, current: &[u32]| {
+            if !record_path && visitor.is_none() {
+                return;
+            }
+            names.clear();
+            names.extend(current.iter().map(|node| self.name(*node)));
+            if let Some(visitor) = visitor.as_mut() {
+                visitor(steps, &names);
+            }
+            if record_path {
+                path.push(names.clone());
             }
         };
+
+        visit(0, &current);
+        for (steps, direction) in (1..=limit).zip(self.directions.iter().cycle()) {
+            for node in current.iter_mut() {
+                *node = self.step(*node, *direction);
+            }
+            visit(steps, &current);
+
+            if current.iter().all(|node| goal.contains(*node)) {
+                return Ok(Some(Walk { steps, path }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Once a walk is back at a node it has been at the start of the
+    // directions before, it repeats forever. Only checking at the start of
+    // the directions can find the cycle a little late, but keeps the visited
+    // states down to one per node.
+    fn find_cycle(&self, start: u32, goal: &NodeSet) -> Cycle {
+        let mut seen = vec![None; self.names.len()];
+        let mut out = Vec::new();
+        let mut node = start;
+        let mut step = 0;
+        loop {
+            if let Some(cycle_start) = seen[node as usize] {
+                let (prefix_hits, hits) = out.into_iter().partition(|hit| *hit < cycle_start);
+                return Cycle {
+                    prefix_hits,
+                    start: cycle_start,
+                    length: step - cycle_start,
+                    hits,
+                };
+            }
+            seen[node as usize] = Some(step);
+
+            for direction in &self.directions {
+                if goal.contains(node) {
+                    out.push(step);
+                }
+                node = self.step(node, *direction);
+                step += 1;
+            }
+        }
     }
 
-    unreachable!();
+    // The same as `walk` without a limit, but works out when the walkers line
+    // up from their cycles rather than stepping through every position.
+    fn ghost_walk(
+        &self,
+        start: impl Fn(&str) -> bool,
+        goal: impl Fn(&str) -> bool,
+    ) -> anyhow::Result<Option<u64>> {
+        let goal = self.node_set(goal);
+        let cycles: Vec<Cycle> = self
+            .starts(start)
+            .into_iter()
+            .map(|start| self.find_cycle(start, &goal))
+            .collect();
+        first_common_hit(&cycles)
+    }
+}
+
+// Every step at which a walk stands on a goal, split into the hits before
+// the walk starts repeating and the hits inside the part that repeats every
+// `length` steps from `start` onwards.
+#[derive(Debug)]
+struct Cycle {
+    prefix_hits: Vec<u64>,
+    start: u64,
+    length: u64,
+    hits: Vec<u64>,
 }
 
-// Copied from: https://github.com/TheAlgorithms/Rust/blob/master/src/math/lcm_of_n_numbers.rs
-pub fn calculate_lcm(nums: &[u64]) -> u64 {
-    if nums.len() == 1 {
-        return nums[0];
+impl Cycle {
+    fn contains(&self, step: u64) -> bool {
+        if step < self.start {
+            self.prefix_hits.contains(&step)
+        } else {
+            self.hits
+                .contains(&(self.start + (step - self.start) % self.length))
+        }
     }
-    let a = nums[0];
-    let b = calculate_lcm(&nums[1..]);
-    a * b / gcd_of_two_numbers(a, b)
 }
 
-// Copied from: https://github.com/TheAlgorithms/Rust/blob/master/src/math/lcm_of_n_numbers.rs
-fn gcd_of_two_numbers(a: u64, b: u64) -> u64 {
+// Returns (g, x, y) where a * x + b * y = g = gcd(a, b)
+fn extended_gcd(a: i128, b: i128) -> (i128, i128, i128) {
     if b == 0 {
-        return a;
+        return (a, 1, 0);
     }
-    gcd_of_two_numbers(b, a % b)
+    let (g, x, y) = extended_gcd(b, a % b);
+    (g, y, x - (a / b) * y)
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    let (directions, mapping) = parse_input(input)?;
+// Combines x = a (mod m) and x = b (mod n) into a single congruence modulo
+// lcm(m, n), if they have any solution in common.
+fn crt(a: u64, m: u64, b: u64, n: u64) -> anyhow::Result<Option<(u64, u64)>> {
+    let (g, p, _) = extended_gcd(m as i128, n as i128);
+    let diff = b as i128 - a as i128;
+    if diff % g != 0 {
+        return Ok(None);
+    }
+    let n_g = n as i128 / g;
+    let lcm = u64::try_from(m as i128 * n_g).context("Cycle lengths overflowed")?;
+    // p * m = g (mod n), so p is the inverse of m / g modulo n / g
+    let k = ((diff / g).rem_euclid(n_g))
+        .checked_mul(p.rem_euclid(n_g))
+        .context("Cycle lengths overflowed")?
+        .rem_euclid(n_g);
+    let x = (a as i128 + m as i128 * k).rem_euclid(lcm as i128);
+    Ok(Some((x as u64, lcm)))
+}
 
-    let mut all_current = Vec::new();
-    for key in mapping.keys() {
-        if key.ends_with('A') {
-            all_current.push(key);
-        }
+// The first step (after at least one) where every walk stands on a goal at
+// the same time.
+fn first_common_hit(cycles: &[Cycle]) -> anyhow::Result<Option<u64>> {
+    if cycles.is_empty() {
+        return Ok(None);
     }
 
-    let mut cycle_lengths = Vec::new();
-    for start in all_current.iter() {
-        let (cycle_start_idx, total_path_length) = find_cycle(&directions, start, &mapping)?;
-        cycle_lengths.push(total_path_length as u64 - cycle_start_idx as u64);
+    // Before every walk has settled into its cycle, a common hit has to be
+    // one of the prefix hits.
+    let early = cycles
+        .iter()
+        .flat_map(|cycle| cycle.prefix_hits.iter().copied())
+        .filter(|step| *step > 0 && cycles.iter().all(|cycle| cycle.contains(*step)))
+        .min();
+    if early.is_some() {
+        return Ok(early);
     }
 
-    let lcm = calculate_lcm(&cycle_lengths);
-    Ok(Some(lcm))
+    // After that every walk hits its goals periodically, so solve for every
+    // combination of hits at once.
+    let mut residues = vec![0];
+    let mut modulus = 1;
+    for cycle in cycles {
+        let mut next = BTreeSet::new();
+        let mut next_modulus = modulus;
+        for residue in &residues {
+            for hit in &cycle.hits {
+                if let Some((x, m)) = crt(*residue, modulus, hit % cycle.length, cycle.length)? {
+                    next.insert(x);
+                    next_modulus = m;
+                }
+            }
+        }
+        residues = next.into_iter().collect();
+        modulus = next_modulus;
+    }
+
+    let settled = cycles
+        .iter()
+        .map(|cycle| cycle.start)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let steps = residues
+        .into_iter()
+        .map(|residue| {
+            if residue >= settled {
+                return Ok(residue);
+            }
+            (settled - residue)
+                .div_ceil(modulus)
+                .checked_mul(modulus)
+                .and_then(|offset| offset.checked_add(residue))
+                .context("Common hit overflowed")
+        })
+        .collect::<anyhow::Result<Vec<u64>>>()?;
+    Ok(steps.into_iter().min())
+}
+
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u32>, anyhow::Error> {
+    let network: Network = input.parse()?;
+    let walk = network.walk(
+        |node| node == "AAA",
+        |node| node == "ZZZ",
+        None,
+        false,
+        None,
+    )?;
+    Ok(walk.map(|walk| walk.steps as u32))
+}
+
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
+    let network: Network = input.parse()?;
+    network.ghost_walk(|node| node.ends_with('A'), |node| node.ends_with('Z'))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // Repeatable random numbers below `n` from a simple LCG
+    fn rng(mut seed: u64) -> impl FnMut(u64) -> u64 {
+        move |n| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        }
+    }
+
     #[test]
     fn test_part_one() -> anyhow::Result<()> {
         let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
@@ -176,4 +415,264 @@ mod tests {
         assert_eq!(result, Some(6));
         Ok(())
     }
+
+    #[test]
+    fn test_misaligned_cycles() -> anyhow::Result<()> {
+        // 11A hits its goal every 2 steps but 22A hits first after 1 step and
+        // then every 3, so lining up the cycle lengths would give 6.
+        let input = "L\n\n\
+            11A = (11B, 11B)\n\
+            11B = (11Z, 11Z)\n\
+            11Z = (11B, 11B)\n\
+            22A = (22Z, 22Z)\n\
+            22Z = (22B, 22B)\n\
+            22B = (22C, 22C)\n\
+            22C = (22Z, 22Z)\n";
+        assert_eq!(part_two(input, RunType::Example)?, Some(4));
+
+        // 11A only reaches its goal once before settling into a loop
+        let input = "L\n\n\
+            11A = (11Z, 11Z)\n\
+            11Z = (11B, 11B)\n\
+            11B = (11B, 11B)\n\
+            22A = (22Z, 22Z)\n\
+            22Z = (22Z, 22Z)\n";
+        assert_eq!(part_two(input, RunType::Example)?, Some(1));
+
+        // Both goals repeat every 2 steps but never at the same time
+        let input = "L\n\n\
+            11A = (11Z, 11Z)\n\
+            11Z = (11B, 11B)\n\
+            11B = (11Z, 11Z)\n\
+            22A = (22B, 22B)\n\
+            22B = (22Z, 22Z)\n\
+            22Z = (22B, 22B)\n";
+        assert_eq!(part_two(input, RunType::Example)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches_simulation() -> anyhow::Result<()> {
+        let mut next = rng(0x2023_0008);
+
+        for _ in 0..300 {
+            let nodes = 2 + next(8);
+            let names: Vec<String> = (0..nodes)
+                .map(|i| format!("N{}{}", i, ['A', 'B', 'Z'][next(3) as usize]))
+                .collect();
+            let directions: String = (0..1 + next(4))
+                .map(|_| if next(2) == 0 { 'L' } else { 'R' })
+                .collect();
+            let mut input = format!("{}\n\n", directions);
+            for name in &names {
+                let left = &names[next(nodes) as usize];
+                let right = &names[next(nodes) as usize];
+                input.push_str(&format!("{} = ({}, {})\n", name, left, right));
+            }
+
+            let network: Network = input.parse()?;
+            let starts = |node: &str| node.ends_with('A');
+            let goals = |node: &str| node.ends_with('Z');
+            let expected = match network.starts(starts).is_empty() {
+                true => None,
+                false => network
+                    .walk(starts, goals, Some(10_000), false, None)?
+                    .map(|walk| walk.steps),
+            };
+
+            let result = part_two(&input, RunType::Example)?;
+            match expected {
+                Some(_) => assert_eq!(result, expected, "{}", input),
+                None => assert!(result.is_none_or(|step| step >= 10_000), "{}", input),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let network: Network = input.parse()?;
+
+        let walk = network
+            .walk(|node| node == "AAA", |node| node == "CCC", None, true, None)?
+            .context("Expected to reach CCC")?;
+        assert_eq!(walk.steps, 1);
+        assert_eq!(walk.path, vec![vec!["AAA"], vec!["CCC"]]);
+        let walk = network
+            .walk(
+                |node| node == "AAA",
+                |node| node == "CCC",
+                None,
+                false,
+                None,
+            )?
+            .context("Expected to reach CCC")?;
+        assert!(walk.path.is_empty());
+
+        let walk = network.walk(
+            |node| node == "AAA",
+            |node| node == "EEE",
+            Some(1000),
+            false,
+            None,
+        )?;
+        assert!(walk.is_none());
+        let walk = network.walk(
+            |node| node == "AAA",
+            |node| node == "EEE",
+            None,
+            false,
+            None,
+        )?;
+        assert!(walk.is_none());
+        let input = "L\n\nAAA = (BBB, BBB)\nBBB = (AAA, AAA)\nZZZ = (ZZZ, ZZZ)\n";
+        assert_eq!(part_one(input, RunType::Example)?, None);
+        assert!(network
+            .walk(|_| false, |_| true, None, false, None)
+            .is_err());
+
+        let mut visited = Vec::new();
+        let mut visitor = |step: u64, nodes: &[&str]| visited.push((step, nodes.join(",")));
+        let walk = network
+            .walk(
+                |node| node == "AAA" || node == "BBB",
+                |node| node == "EEE" || node == "ZZZ",
+                Some(10),
+                false,
+                Some(&mut visitor),
+            )?
+            .context("Expected to reach EEE and ZZZ")?;
+        assert_eq!(walk.steps, 2);
+        assert_eq!(
+            visited,
+            vec![
+                (0, "AAA,BBB".to_string()),
+                (1, "CCC,EEE".to_string()),
+                (2, "ZZZ,EEE".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let network: Network = input.parse()?;
+        assert_eq!(network.names.len(), 7);
+        assert_eq!(network.name(network.step(0, Direction::Right)), "CCC");
+
+        let goals = network.node_set(|node| node.starts_with('D') || node.starts_with('Z'));
+        let members: Vec<&str> = (0..7)
+            .filter(|node| goals.contains(*node))
+            .map(|node| network.name(node))
+            .collect();
+        assert_eq!(members, vec!["DDD", "ZZZ"]);
+
+        assert!("LR\n\nAAA = (AAA, BBB)\n".parse::<Network>().is_err());
+        assert!("\n\nAAA = (AAA, AAA)\n".parse::<Network>().is_err());
+        Ok(())
+    }
+
+    // Checks walks and cycles on the interned network against stepping
+    // through the string map it was built from, and reports how long each
+    // takes. Run with:
+    // cargo test --release --bin 08 bench_network -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_network() -> anyhow::Result<()> {
+        let mut next = rng(0x2023_0008);
+
+        let nodes = 1_000_000;
+        let name = |i: u64| match i % 100 {
+            0 => format!("{:06}A", i),
+            99 => format!("{:06}Z", i),
+            _ => format!("{:06}B", i),
+        };
+        let mut input: String = (0..20_000)
+            .map(|_| if next(2) == 0 { 'L' } else { 'R' })
+            .collect();
+        input.push_str("\n\n");
+        for i in 0..nodes {
+            let left = name(next(nodes));
+            let right = name(next(nodes));
+            input.push_str(&format!("{} = ({}, {})\n", name(i), left, right));
+        }
+
+        let start = std::time::Instant::now();
+        let network: Network = input.parse()?;
+        let compile_time = start.elapsed();
+        let (directions, mapping) = parse_input(&input)?;
+
+        let steps = 10_000_000;
+        let start = std::time::Instant::now();
+        let mut node = 0;
+        for direction in network.directions.iter().cycle().take(steps) {
+            node = network.step(node, *direction);
+        }
+        let interned_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let first = name(0);
+        let mut current = first.as_str();
+        for direction in directions.iter().cycle().take(steps) {
+            let (left, right) = &mapping[current];
+            current = match direction {
+                Direction::Left => left,
+                Direction::Right => right,
+            };
+        }
+        let string_time = start.elapsed();
+        assert_eq!(network.name(node), current);
+
+        let start = std::time::Instant::now();
+        let goal = network.node_set(|node| node.ends_with('Z'));
+        let cycles: Vec<Cycle> = (0..10)
+            .map(|i| network.find_cycle(i * 100, &goal))
+            .collect();
+        let cycle_time = start.elapsed();
+
+        // Replaying each walk, it hits a goal exactly when its cycle says so
+        // and is back at the same node and direction a whole cycle later.
+        for (i, cycle) in cycles.iter().enumerate() {
+            assert_eq!(cycle.length % directions.len() as u64, 0);
+            let first = name(i as u64 * 100);
+            let mut current = first.as_str();
+            let mut node = i as u32 * 100;
+            let mut at_cycle_start = None;
+            let replay = (0..cycle.start + cycle.length).zip(directions.iter().cycle());
+            for (step, direction) in replay {
+                if step == cycle.start {
+                    at_cycle_start = Some(node);
+                }
+                assert_eq!(goal.contains(node), cycle.contains(step));
+                node = network.step(node, *direction);
+                // Stepping through the strings is slow so only check a couple
+                if i < 2 {
+                    assert_eq!(current.ends_with('Z'), cycle.contains(step));
+                    let (left, right) = &mapping[current];
+                    current = match direction {
+                        Direction::Left => left,
+                        Direction::Right => right,
+                    };
+                }
+            }
+            assert_eq!(Some(node), at_cycle_start);
+            if i < 2 {
+                assert_eq!(network.name(node), current);
+            }
+        }
+
+        println!(
+            "{} nodes: compile {:?}, {} steps interned {:?}, strings {:?}, {} cycles {:?}",
+            nodes,
+            compile_time,
+            steps,
+            interned_time,
+            string_time,
+            cycles.len(),
+            cycle_time
+        );
+        Ok(())
+    }
 }
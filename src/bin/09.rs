@@ -1,80 +1,415 @@
 use advent_of_code::template::RunType;
-use nom::character::complete::{i32 as nom_i32, line_ending, space1};
-use nom::combinator::all_consuming;
+use anyhow::{anyhow, Context};
+use nom::character::complete::{char, digit1, line_ending, space1};
+use nom::combinator::{all_consuming, map_res, opt, recognize};
 use nom::multi::{many1, separated_list1};
+use nom::sequence::pair;
 use nom::{Finish, IResult};
+use num_bigint::BigInt;
+use num_traits::{One, Signed, Zero};
+use std::collections::VecDeque;
+use std::str::FromStr;
 
 advent_of_code::solution!(9);
 
 #[derive(Debug, Clone)]
-struct Sequence(Vec<i32>);
-
-type FnGetNext = fn(&Sequence, i32) -> i32;
-
-impl Sequence {
-    fn step(&self) -> (Sequence, bool) {
-        let mut out = Vec::new();
-        let mut all_zero = true;
-        for window in self.0.windows(2) {
-            let diff = window[1] - window[0];
-            if diff != 0 {
-                all_zero = false;
-            }
-            out.push(diff);
+struct Sequence {
+    line: usize,
+    values: Vec<BigInt>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SequenceError {
+    Empty { line: usize },
+    // The differences got down to a single value without ever all being zero
+    NotPolynomial { line: usize },
+}
+
+impl std::fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequenceError::Empty { line } => write!(f, "Sequence on line {} is empty", line),
+            SequenceError::NotPolynomial { line } => write!(
+                f,
+                "Sequence on line {} never reaches a row of zero differences",
+                line
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SequenceError {}
+
+fn differences(values: &[BigInt]) -> (Vec<BigInt>, bool) {
+    let mut out = Vec::new();
+    let mut all_zero = true;
+    for window in values.windows(2) {
+        let diff = &window[1] - &window[0];
+        if !diff.is_zero() {
+            all_zero = false;
         }
+        out.push(diff);
+    }
+
+    (out, all_zero)
+}
 
-        (Sequence(out), all_zero)
+fn gcd(a: &BigInt, b: &BigInt) -> BigInt {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while !b.is_zero() {
+        let r = &a % &b;
+        a = b;
+        b = r;
     }
+    a
+}
 
-    fn extrapolate(self, get_next: FnGetNext) -> i32 {
-        let mut layers = vec![self];
-        loop {
-            let (next_layer, all_zero) = layers.last().unwrap().step();
-            layers.push(next_layer);
-            if all_zero {
+// An exact fraction, always in lowest terms with a positive denominator
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rational {
+    num: BigInt,
+    den: BigInt,
+}
+
+impl Rational {
+    fn new(num: BigInt, den: BigInt) -> Self {
+        let divisor = gcd(&num, &den);
+        let divisor = if den.is_negative() { -divisor } else { divisor };
+        Rational {
+            num: num / &divisor,
+            den: den / divisor,
+        }
+    }
+}
+
+impl From<BigInt> for Rational {
+    fn from(value: BigInt) -> Self {
+        Rational {
+            num: value,
+            den: One::one(),
+        }
+    }
+}
+
+impl From<i64> for Rational {
+    fn from(value: i64) -> Self {
+        Rational {
+            num: value.into(),
+            den: One::one(),
+        }
+    }
+}
+
+impl std::fmt::Display for Rational {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.den.is_one() {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+impl std::ops::Add for &Rational {
+    type Output = Rational;
+
+    fn add(self, other: &Rational) -> Rational {
+        Rational::new(
+            &self.num * &other.den + &other.num * &self.den,
+            &self.den * &other.den,
+        )
+    }
+}
+
+impl std::ops::Sub for &Rational {
+    type Output = Rational;
+
+    fn sub(self, other: &Rational) -> Rational {
+        Rational::new(
+            &self.num * &other.den - &other.num * &self.den,
+            &self.den * &other.den,
+        )
+    }
+}
+
+impl std::ops::Mul for &Rational {
+    type Output = Rational;
+
+    fn mul(self, other: &Rational) -> Rational {
+        Rational::new(&self.num * &other.num, &self.den * &other.den)
+    }
+}
+
+impl std::ops::Div for &Rational {
+    type Output = Rational;
+
+    fn div(self, other: &Rational) -> Rational {
+        Rational::new(&self.num * &other.den, &self.den * &other.num)
+    }
+}
+
+// The polynomial through every value of a sequence, kept in Newton's forward
+// difference form: p(x) = sum of terms[k] * C(x, k), where terms[k] is the
+// first value of the k-th row of differences.
+#[derive(Debug, Clone)]
+struct Polynomial {
+    terms: Vec<BigInt>,
+}
+
+impl Polynomial {
+    fn fit(sequence: &Sequence) -> Result<Self, SequenceError> {
+        let line = sequence.line;
+        if sequence.values.is_empty() {
+            return Err(SequenceError::Empty { line });
+        }
+
+        let mut terms = Vec::new();
+        let mut layer = sequence.values.clone();
+        while let Some(first) = layer.first() {
+            terms.push(first.clone());
+            let (next_layer, all_zero) = differences(&layer);
+            if next_layer.is_empty() {
                 break;
             }
+            if all_zero {
+                return Ok(Polynomial { terms });
+            }
+            layer = next_layer;
+        }
+        Err(SequenceError::NotPolynomial { line })
+    }
+
+    #[allow(dead_code)]
+    fn degree(&self) -> usize {
+        self.terms.len().saturating_sub(1)
+    }
+
+    // C(x, k) is a whole number for any whole x, including negative ones, so
+    // this never needs fractions.
+    fn evaluate(&self, x: &BigInt) -> BigInt {
+        let mut out = BigInt::zero();
+        let mut choose = BigInt::one();
+        for (k, term) in self.terms.iter().enumerate() {
+            out += term * &choose;
+            choose = choose * (x - k) / (k + 1);
+        }
+        out
+    }
+
+    // The coefficients of 1, x, x^2, ... up to the degree
+    #[allow(dead_code)]
+    fn coefficients(&self) -> Vec<Rational> {
+        let mut out = vec![(BigInt::zero(), BigInt::one()); self.terms.len()];
+        // x (x - 1) ... (x - k + 1) as integer coefficients, and k!
+        let mut falling = vec![BigInt::one()];
+        let mut factorial = BigInt::one();
+        for (k, term) in self.terms.iter().enumerate() {
+            if k > 0 {
+                factorial *= k;
+                let mut next = vec![BigInt::zero(); falling.len() + 1];
+                for (i, coefficient) in falling.iter().enumerate() {
+                    next[i + 1] += coefficient;
+                    next[i] -= coefficient * (k - 1);
+                }
+                falling = next;
+            }
+            for (i, coefficient) in falling.iter().enumerate() {
+                // Add term * coefficient / k! to what we have so far
+                let (num, den) = &out[i];
+                out[i] = (
+                    num * &factorial + term * coefficient * den,
+                    den * &factorial,
+                );
+            }
         }
+        out.into_iter()
+            .map(|(num, den)| Rational::new(num, den))
+            .collect()
+    }
+}
+
+// a[n] = coefficients[0] * a[n - 1] + ... + coefficients[L - 1] * a[n - L],
+// starting from the values of the sequence it was found in.
+#[derive(Debug, Clone)]
+struct Recurrence {
+    coefficients: Vec<Rational>,
+    values: Vec<Rational>,
+}
+
+impl Recurrence {
+    // Berlekamp-Massey, which finds the shortest recurrence that generates
+    // the whole sequence. It's only trusted when the sequence is at least
+    // twice as long as the recurrence, otherwise any sequence would fit.
+    fn fit(sequence: &Sequence) -> Option<Self> {
+        let values: Vec<Rational> = sequence
+            .values
+            .iter()
+            .cloned()
+            .map(Rational::from)
+            .collect();
+        let zero = Rational::from(0);
+
+        // The connection polynomial 1 - c[0] x - c[1] x^2 - ... and the one
+        // from before the last length change
+        let mut current = vec![Rational::from(1)];
+        let mut previous = vec![Rational::from(1)];
+        let mut length = 0;
+        let mut shift = 1;
+        let mut previous_discrepancy = Rational::from(1);
+        for n in 0..values.len() {
+            let discrepancy = (1..=length).fold(values[n].clone(), |acc, i| {
+                &acc + &(&current[i] * &values[n - i])
+            });
+            if discrepancy == zero {
+                shift += 1;
+                continue;
+            }
 
-        let mut next_value = 0;
-        for layer in layers.iter().rev() {
-            next_value = get_next(layer, next_value);
+            let scale = &discrepancy / &previous_discrepancy;
+            let mut next = current.clone();
+            next.resize(next.len().max(previous.len() + shift), zero.clone());
+            for (i, coefficient) in previous.iter().enumerate() {
+                next[i + shift] = &next[i + shift] - &(&scale * coefficient);
+            }
+            if 2 * length <= n {
+                previous = std::mem::replace(&mut current, next);
+                length = n + 1 - length;
+                previous_discrepancy = discrepancy;
+                shift = 1;
+            } else {
+                current = next;
+                shift += 1;
+            }
         }
 
-        next_value
+        if 2 * length > values.len() {
+            return None;
+        }
+        current.resize(length + 1, zero.clone());
+        Some(Recurrence {
+            coefficients: current[1..].iter().map(|c| &zero - c).collect(),
+            values,
+        })
+    }
+
+    // Runs the recurrence forwards, or backwards for negative indexes as long
+    // as the last coefficient can be divided out.
+    fn evaluate(&self, x: &BigInt) -> anyhow::Result<BigInt> {
+        let length = self.coefficients.len();
+        let mut values: VecDeque<Rational> = self.values.iter().cloned().collect();
+        let mut first = BigInt::zero();
+        if x.is_negative() {
+            let last = self.coefficients.last().filter(|c| !c.num.is_zero());
+            let last = last.context("Recurrence can't be run backwards")?;
+            while &first > x {
+                let known = (1..length).fold(values[length - 1].clone(), |acc, i| {
+                    &acc - &(&self.coefficients[i - 1] * &values[length - 1 - i])
+                });
+                values.push_front(&known / last);
+                first -= 1;
+            }
+        } else {
+            let index = usize::try_from(x).context("Index is too far away")?;
+            while values.len() <= index {
+                let next = (0..length).fold(Rational::from(0), |acc, i| {
+                    &acc + &(&self.coefficients[i] * &values[values.len() - 1 - i])
+                });
+                values.push_back(next);
+            }
+        }
+
+        let offset = usize::try_from(x - first).context("Index is too far away")?;
+        let value = &values[offset];
+        if !value.den.is_one() {
+            return Err(anyhow!("Recurrence gives a fraction: {}", value));
+        }
+        Ok(value.num.clone())
     }
 }
 
-fn nom_line(input: &str) -> IResult<&str, Sequence> {
-    let (input, result) = separated_list1(space1, nom_i32)(input)?;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    // Only accept sequences that are polynomials
+    None,
+    // Try a linear recurrence for sequences that aren't polynomials
+    Recurrence,
+}
+
+#[derive(Debug, Clone)]
+enum Model {
+    Polynomial(Polynomial),
+    Recurrence(Recurrence),
+}
+
+impl Model {
+    fn fit(sequence: &Sequence, fallback: Fallback) -> Result<Self, SequenceError> {
+        match (Polynomial::fit(sequence), fallback) {
+            (Ok(polynomial), _) => Ok(Model::Polynomial(polynomial)),
+            (Err(e @ SequenceError::NotPolynomial { .. }), Fallback::Recurrence) => {
+                Recurrence::fit(sequence).map(Model::Recurrence).ok_or(e)
+            }
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    fn evaluate(&self, x: &BigInt) -> anyhow::Result<BigInt> {
+        match self {
+            Model::Polynomial(polynomial) => Ok(polynomial.evaluate(x)),
+            Model::Recurrence(recurrence) => recurrence.evaluate(x),
+        }
+    }
+}
+
+fn nom_value(input: &str) -> IResult<&str, BigInt> {
+    map_res(recognize(pair(opt(char('-')), digit1)), BigInt::from_str)(input)
+}
+
+fn nom_line(input: &str) -> IResult<&str, Vec<BigInt>> {
+    let (input, result) = separated_list1(space1, nom_value)(input)?;
     let (input, _) = line_ending(input)?;
 
-    Ok((input, Sequence(result)))
+    Ok((input, result))
 }
 
 fn parse_input(input: &str) -> anyhow::Result<Vec<Sequence>> {
     match all_consuming(many1(nom_line))(input).finish() {
-        Ok(output) => Ok(output.1),
+        Ok(output) => Ok(output
+            .1
+            .into_iter()
+            .enumerate()
+            .map(|(i, values)| Sequence {
+                line: i + 1,
+                values,
+            })
+            .collect()),
         Err(e) => Err(nom::error::Error::new(e.input.to_string(), e.code).into()),
     }
 }
 
-fn solve(input: &str, get_next: FnGetNext) -> anyhow::Result<Option<i32>> {
+// Sums every sequence's value at the index picked from its length
+fn solve(
+    input: &str,
+    index: fn(usize) -> BigInt,
+    fallback: Fallback,
+) -> anyhow::Result<Option<BigInt>> {
     let data = parse_input(input)?;
 
-    let mut out = 0;
+    let mut out = BigInt::zero();
     for row in data.into_iter() {
-        out += row.extrapolate(get_next);
+        let model = Model::fit(&row, fallback)?;
+        out += model
+            .evaluate(&index(row.values.len()))
+            .with_context(|| format!("Failed to extrapolate line {}", row.line))?;
     }
     Ok(Some(out))
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<i32>, anyhow::Error> {
-    solve(input, |seq, next| seq.0.last().unwrap() + next)
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<BigInt>, anyhow::Error> {
+    solve(input, |len| len.into(), Fallback::None)
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<i32>, anyhow::Error> {
-    solve(input, |seq, next| seq.0.first().unwrap() - next)
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<BigInt>, anyhow::Error> {
+    solve(input, |_| (-1).into(), Fallback::None)
 }
 
 #[cfg(test)]
@@ -85,7 +420,7 @@ mod tests {
     fn test_part_one() -> anyhow::Result<()> {
         let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
         let result = part_one(input, RunType::Example)?;
-        assert_eq!(result, Some(114));
+        assert_eq!(result, Some(114.into()));
         Ok(())
     }
 
@@ -93,7 +428,105 @@ mod tests {
     fn test_part_two() -> anyhow::Result<()> {
         let input = &advent_of_code::template::read_file_part("examples", DAY, 2);
         let result = part_two(input, RunType::Example)?;
-        assert_eq!(result, Some(2));
+        assert_eq!(result, Some(2.into()));
+        Ok(())
+    }
+
+    fn sequence(values: &[i64]) -> Sequence {
+        Sequence {
+            line: 1,
+            values: values.iter().map(|v| (*v).into()).collect(),
+        }
+    }
+
+    fn fit(values: &[i64]) -> Polynomial {
+        Polynomial::fit(&sequence(values)).unwrap()
+    }
+
+    #[test]
+    fn test_polynomial() -> anyhow::Result<()> {
+        // 3x^2 - 2x + 5
+        let polynomial = fit(&[5, 6, 13, 26, 45]);
+        assert_eq!(polynomial.degree(), 2);
+        assert_eq!(
+            polynomial.coefficients(),
+            vec![5.into(), (-2).into(), 3.into()]
+        );
+        assert_eq!(polynomial.evaluate(&(-3).into()), 38.into());
+        let far: BigInt = BigInt::from(10).pow(30);
+        assert_eq!(
+            polynomial.evaluate(&far),
+            BigInt::from(3) * &far * &far - BigInt::from(2) * &far + 5
+        );
+
+        // The triangle numbers are x (x + 1) / 2
+        let polynomial = fit(&[0, 1, 3, 6, 10, 15]);
+        assert_eq!(polynomial.degree(), 2);
+        let half = Rational::new(1.into(), 2.into());
+        assert_eq!(
+            polynomial.coefficients(),
+            vec![0.into(), half.clone(), half]
+        );
+        assert_eq!(polynomial.coefficients()[1].to_string(), "1/2");
+        assert_eq!(polynomial.evaluate(&(-5).into()), 10.into());
+
+        assert_eq!(fit(&[7, 7, 7]).degree(), 0);
+        assert_eq!(fit(&[7, 7, 7]).coefficients(), vec![7.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_values() -> anyhow::Result<()> {
+        // x^9 - 9x overflows an i32 difference long before the end
+        let values: Vec<BigInt> = (0..40_i64)
+            .map(|x| BigInt::from(x).pow(9) - 9 * x)
+            .collect();
+        let polynomial = Polynomial::fit(&Sequence {
+            line: 1,
+            values: values.clone(),
+        })?;
+        assert_eq!(polynomial.degree(), 9);
+
+        let mut input = values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        input.push('\n');
+        let expected = BigInt::from(40).pow(9) - 9 * 40;
+        assert_eq!(part_one(&input, RunType::Example)?, Some(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_polynomial() -> anyhow::Result<()> {
+        let input = "0 1 4 9 16\n1 2 4 8 16 32\n";
+        let err = part_one(input, RunType::Example).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SequenceError>(),
+            Some(&SequenceError::NotPolynomial { line: 2 })
+        );
+        assert!(Polynomial::fit(&sequence(&[5])).is_err());
+        assert_eq!(
+            Polynomial::fit(&sequence(&[])).unwrap_err(),
+            SequenceError::Empty { line: 1 }
+        );
+
+        // Powers of two double every step, in either direction
+        assert_eq!(
+            solve(input, |len| len.into(), Fallback::Recurrence)?,
+            Some((25 + 64).into())
+        );
+        let doubling = Model::fit(&sequence(&[3, 6, 12, 24]), Fallback::Recurrence)?;
+        assert_eq!(doubling.evaluate(&(-1).into()).ok(), None);
+        assert_eq!(doubling.evaluate(&10.into())?, (3 * 1024).into());
+
+        let fibonacci = Model::fit(&sequence(&[2, 1, 3, 4, 7, 11, 18]), Fallback::Recurrence)?;
+        assert_eq!(fibonacci.evaluate(&10.into())?, 123.into());
+        assert_eq!(fibonacci.evaluate(&(-3).into())?, (-4).into());
+
+        // Too short to trust any recurrence
+        assert!(Model::fit(&sequence(&[1, 2, 5]), Fallback::Recurrence).is_err());
         Ok(())
     }
 }
@@ -221,49 +221,80 @@ impl PipeMap {
         })
     }
 
+    // Follows the pipes from the start until we are back there, giving every
+    // tile in the loop in order.
     fn get_loop<'a>(&'a self, start: &'a Location) -> Result<PipeLoop<'a>> {
         let mut nodes = BTreeSet::new();
-        nodes.insert(start.clone());
-
-        let directions = self.pipes.get(start).as_ref().unwrap().directions();
-        let mut current = self.pipes.go_direction(start, &directions[0]).unwrap();
-        while &current != start {
+        let mut path = Vec::new();
+
+        let mut current = start.clone();
+        let mut direction = self
+            .pipes
+            .get(start)
+            .as_ref()
+            .context("Expected a pipe at the start")?
+            .directions()[0]
+            .clone();
+        loop {
             nodes.insert(current.clone());
-
-            let directions = self.pipes.get(&current).as_ref().unwrap().directions();
-            let one = self.pipes.go_direction(&current, &directions[0]).unwrap();
-            let two = self.pipes.go_direction(&current, &directions[1]).unwrap();
-            match (nodes.contains(&one), nodes.contains(&two)) {
-                (true, false) => {
-                    current = two;
-                }
-                (false, true) => {
-                    current = one;
-                }
-                (false, false) => {
-                    return Err(anyhow!("Found disjointed node at {:?}", current));
-                }
-                (true, true) => {
-                    if &one == start || &two == start {
-                        break;
-                    } else {
-                        return Err(anyhow!("Found loop node at {:?}", current));
-                    }
-                }
+            path.push(current.clone());
+
+            let next = self
+                .pipes
+                .go_direction(&current, &direction)
+                .with_context(|| format!("Pipe at {:?} leads off the map", current))?;
+            if &next == start {
+                break;
+            }
+            if nodes.contains(&next) {
+                return Err(anyhow!("Found loop node at {:?}", next));
             }
+
+            // The way out is whichever end we didn't come in through
+            let came_from = direction.invert();
+            let pipe_type = self
+                .pipes
+                .get(&next)
+                .as_ref()
+                .filter(|pipe_type| pipe_type.has_direction(&came_from))
+                .with_context(|| format!("Found disjointed node at {:?}", next))?;
+            direction = pipe_type
+                .directions()
+                .into_iter()
+                .find(|other| other != &came_from)
+                .context("Expected pipe to have two ends")?;
+            current = next;
+        }
+
+        let start_pipe = self.pipes.get(start).as_ref();
+        if !start_pipe.is_some_and(|pipe_type| pipe_type.has_direction(&direction.invert())) {
+            return Err(anyhow!("Loop doesn't connect back to the start"));
         }
 
         Ok(PipeLoop {
             map: &self.pipes,
             all_nodes: nodes,
+            path,
         })
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AreaMethod {
+    // Cross the loop row by row, tracking whether we are inside
+    Scanline,
+    // The area of the loop as a polygon, less its boundary by Pick's theorem
+    Shoelace,
+    // Fill from outside on a grid at twice the resolution, where the gaps
+    // between tiles let the fill squeeze between pipes
+    FloodFill,
+}
+
 #[derive(Debug)]
 struct PipeLoop<'a> {
     map: &'a Map<Option<PipeType>>,
     all_nodes: BTreeSet<Location>,
+    path: Vec<Location>,
 }
 
 impl<'a> PipeLoop<'a> {
@@ -285,24 +316,130 @@ impl<'a> PipeLoop<'a> {
         });
         PipeMap { pipes: new_map }
     }
+
+    // Every tile that isn't part of the loop, split into inside and outside
+    fn scanline(&self) -> (BTreeSet<Location>, BTreeSet<Location>) {
+        let mut inside_nodes = BTreeSet::new();
+        let mut outside_nodes = BTreeSet::new();
+
+        for row in self.map.iter() {
+            let mut inside = false;
+            let mut elbow = None;
+            for (loc, _) in row {
+                if let Some(pipe_type) = self.get(&loc) {
+                    match pipe_type {
+                        PipeType::Horizontal => continue,
+                        PipeType::Vertical => {
+                            inside = !inside;
+                        }
+                        PipeType::NorthEast => {
+                            inside = !inside;
+                            elbow = Some(pipe_type);
+                        }
+                        PipeType::SouthEast => {
+                            inside = !inside;
+                            elbow = Some(pipe_type);
+                        }
+                        PipeType::NorthWest => {
+                            if elbow != Some(&PipeType::SouthEast) {
+                                inside = !inside;
+                            }
+                            elbow = Some(pipe_type);
+                        }
+                        PipeType::SouthWest => {
+                            if elbow != Some(&PipeType::NorthEast) {
+                                inside = !inside;
+                            }
+                            elbow = Some(pipe_type);
+                        }
+                    }
+                } else if inside {
+                    inside_nodes.insert(loc);
+                } else {
+                    outside_nodes.insert(loc);
+                }
+            }
+        }
+        (inside_nodes, outside_nodes)
+    }
+
+    // Pick's theorem says area = inside + boundary / 2 - 1 for a polygon on
+    // whole number points, and every tile of the loop is on the boundary.
+    fn shoelace(&self) -> usize {
+        let mut twice_area: i64 = 0;
+        for (i, one) in self.path.iter().enumerate() {
+            let two = &self.path[(i + 1) % self.path.len()];
+            twice_area += one.0 as i64 * two.1 as i64 - two.0 as i64 * one.1 as i64;
+        }
+        ((twice_area.unsigned_abs() as usize + 2).saturating_sub(self.path.len())) / 2
+    }
+
+    fn flood_fill(&self) -> (BTreeSet<Location>, BTreeSet<Location>) {
+        // Tile (row, col) sits at (2 * row + 1, 2 * col + 1) with a gap on
+        // every side, which is only blocked where the loop runs through it.
+        let rows = self.map.0.len();
+        let cols = self.map.0.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut blocked = vec![vec![false; 2 * cols + 1]; 2 * rows + 1];
+        for (i, one) in self.path.iter().enumerate() {
+            let two = &self.path[(i + 1) % self.path.len()];
+            blocked[2 * one.0 + 1][2 * one.1 + 1] = true;
+            blocked[one.0 + two.0 + 1][one.1 + two.1 + 1] = true;
+        }
+
+        let mut reached = vec![vec![false; 2 * cols + 1]; 2 * rows + 1];
+        let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+        reached[0][0] = true;
+        while let Some((row, col)) = pending.pop() {
+            let neighbours = [
+                (row.wrapping_sub(1), col),
+                (row + 1, col),
+                (row, col.wrapping_sub(1)),
+                (row, col + 1),
+            ];
+            for (row, col) in neighbours {
+                if row < reached.len()
+                    && col < reached[row].len()
+                    && !blocked[row][col]
+                    && !reached[row][col]
+                {
+                    reached[row][col] = true;
+                    pending.push((row, col));
+                }
+            }
+        }
+
+        let mut inside_nodes = BTreeSet::new();
+        let mut outside_nodes = BTreeSet::new();
+        for (loc, _) in self.map.iter().flatten() {
+            if self.all_nodes.contains(&loc) {
+                continue;
+            }
+            if reached[2 * loc.0 + 1][2 * loc.1 + 1] {
+                outside_nodes.insert(loc);
+            } else {
+                inside_nodes.insert(loc);
+            }
+        }
+        (inside_nodes, outside_nodes)
+    }
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<usize>> {
+fn load_loop(input: &str) -> Result<(Location, PipeMap)> {
     let raw_map: RawPipeMap = input.parse().context("Failed to parse map")?;
-    let (start, map) = raw_map
+    raw_map
         .resolve_pipe_map()
-        .context("Failed to resolve pipe map")?;
+        .context("Failed to resolve pipe map")
+}
+
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<usize>> {
+    let (start, map) = load_loop(input)?;
 
     let pipe_loop = map.get_loop(&start)?;
-    Ok(Some(pipe_loop.all_nodes.len() / 2))
+    Ok(Some(pipe_loop.path.len() / 2))
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<usize>> {
-    let mut out = 0;
-    let raw_map: RawPipeMap = input.parse().context("Failed to parse map")?;
-    let (start, map) = raw_map
-        .resolve_pipe_map()
-        .context("Failed to resolve pipe map")?;
+fn solve(input: &str, method: AreaMethod) -> Result<Option<usize>> {
+    let (start, map) = load_loop(input)?;
 
     let pipe_loop = map.get_loop(&start)?;
 
@@ -310,53 +447,20 @@ pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<usize>> {
         .loop_only_map()
         .print(&BTreeSet::new(), &BTreeSet::new());
 
-    let mut inside_nodes = BTreeSet::new();
-    let mut outside_nodes = BTreeSet::new();
-
-    for row in map.pipes.iter() {
-        let mut inside = false;
-        let mut elbow = None;
-        for (loc, _) in row {
-            if let Some(pipe_type) = pipe_loop.get(&loc) {
-                match pipe_type {
-                    PipeType::Horizontal => continue,
-                    PipeType::Vertical => {
-                        inside = !inside;
-                    }
-                    PipeType::NorthEast => {
-                        inside = !inside;
-                        elbow = Some(pipe_type);
-                    }
-                    PipeType::SouthEast => {
-                        inside = !inside;
-                        elbow = Some(pipe_type);
-                    }
-                    PipeType::NorthWest => {
-                        if elbow != Some(&PipeType::SouthEast) {
-                            inside = !inside;
-                        }
-                        elbow = Some(pipe_type);
-                    }
-                    PipeType::SouthWest => {
-                        if elbow != Some(&PipeType::NorthEast) {
-                            inside = !inside;
-                        }
-                        elbow = Some(pipe_type);
-                    }
-                }
-            } else if inside {
-                out += 1;
-                inside_nodes.insert(loc);
-            } else {
-                outside_nodes.insert(loc);
-            }
-        }
-    }
+    let (inside_nodes, outside_nodes) = match method {
+        AreaMethod::Scanline => pipe_loop.scanline(),
+        AreaMethod::FloodFill => pipe_loop.flood_fill(),
+        AreaMethod::Shoelace => return Ok(Some(pipe_loop.shoelace())),
+    };
     pipe_loop
         .loop_only_map()
         .print(&inside_nodes, &outside_nodes);
 
-    Ok(Some(out))
+    Ok(Some(inside_nodes.len()))
+}
+
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<usize>> {
+    solve(input, AreaMethod::Scanline)
 }
 
 #[cfg(test)]
@@ -378,4 +482,80 @@ mod tests {
         assert_eq!(result, Some(10));
         Ok(())
     }
+
+    #[test]
+    fn test_area_methods() -> anyhow::Result<()> {
+        let cases = [
+            (
+                advent_of_code::template::read_file_part("examples", DAY, 1),
+                1,
+            ),
+            (
+                advent_of_code::template::read_file_part("examples", DAY, 2),
+                10,
+            ),
+            (
+                "...........\n\
+                 .S-------7.\n\
+                 .|F-----7|.\n\
+                 .||.....||.\n\
+                 .||.....||.\n\
+                 .|L-7.F-J|.\n\
+                 .|..|.|..|.\n\
+                 .L--J.L--J.\n\
+                 ...........\n"
+                    .to_string(),
+                4,
+            ),
+            // The fill has to squeeze between the pipes in the middle
+            (
+                "..........\n\
+                 .S------7.\n\
+                 .|F----7|.\n\
+                 .||....||.\n\
+                 .||....||.\n\
+                 .|L-7F-J|.\n\
+                 .|..||..|.\n\
+                 .L--JL--J.\n\
+                 ..........\n"
+                    .to_string(),
+                4,
+            ),
+            (
+                ".F----7F7F7F7F-7....\n\
+                 .|F--7||||||||FJ....\n\
+                 .||.FJ||||||||L7....\n\
+                 FJL7L7LJLJ||LJ.L-7..\n\
+                 L--J.L7...LJS7F-7L7.\n\
+                 ....F-J..F7FJ|L7L7L7\n\
+                 ....L7.F7||L7|.L7L7|\n\
+                 .....|FJLJ|FJ|F7|.LJ\n\
+                 ....FJL-7.||.||||...\n\
+                 ....L---J.LJ.LJLJ...\n"
+                    .to_string(),
+                8,
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let (start, map) = load_loop(&input)?;
+            let pipe_loop = map.get_loop(&start)?;
+            assert_eq!(pipe_loop.path[0], start);
+            assert_eq!(pipe_loop.path.len(), pipe_loop.all_nodes.len());
+
+            let (scanline, _) = pipe_loop.scanline();
+            let (flood_fill, _) = pipe_loop.flood_fill();
+            assert_eq!(scanline.len(), expected);
+            assert_eq!(flood_fill, scanline);
+            assert_eq!(pipe_loop.shoelace(), expected);
+            for method in [
+                AreaMethod::Scanline,
+                AreaMethod::Shoelace,
+                AreaMethod::FloodFill,
+            ] {
+                assert_eq!(solve(&input, method)?, Some(expected));
+            }
+        }
+        Ok(())
+    }
 }